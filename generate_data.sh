@@ -1,4 +1,4 @@
-#bin/bash
+#!/bin/bash
 for variable in {1..10500}
 do
     echo "deposit, 1, $variable, 1.0"
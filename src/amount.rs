@@ -0,0 +1,229 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Decimal places every amount is kept at
+pub const AMOUNT_SCALE: usize = 4;
+const UNITS_PER_WHOLE: i64 = 10_000;
+
+/// Money as a whole number of ten-thousandths. Parsing rejects more than four decimal
+/// places and arithmetic is checked, so malformed or overflowing amounts are rejected
+/// instead of rounded.
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub fn from_units(units: i64) -> Self {
+        Amount(units)
+    }
+
+    /// Ten-thousandths
+    pub fn units(self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// `checked_add` failing with a `Recoverable` `AmountOverflow`
+    pub fn try_add(self, other: Amount) -> Result<Amount, RuntimeError> {
+        self.checked_add(other)
+            .ok_or_else(|| overflow_error(self, "+", other))
+    }
+
+    /// `checked_sub` failing with a `Recoverable` `AmountOverflow`
+    pub fn try_sub(self, other: Amount) -> Result<Amount, RuntimeError> {
+        self.checked_sub(other)
+            .ok_or_else(|| overflow_error(self, "-", other))
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Renders with exactly `scale` decimal places, rounding half away from zero. An amount
+    /// rounding to zero has no sign.
+    pub fn to_string_with_scale(self, scale: usize) -> String {
+        let mut units = self.0.unsigned_abs();
+        if scale < AMOUNT_SCALE {
+            let step = 10u64.pow((AMOUNT_SCALE - scale) as u32);
+            units = (units + step / 2) / step * step;
+        }
+        let sign = if self.0 < 0 && units != 0 { "-" } else { "" };
+        let whole = units / UNITS_PER_WHOLE as u64;
+        let fraction = format!("{:04}", units % UNITS_PER_WHOLE as u64);
+        match scale {
+            0 => format!("{}{}", sign, whole),
+            x if x <= AMOUNT_SCALE => format!("{}{}.{}", sign, whole, &fraction[..x]),
+            x => format!(
+                "{}{}.{}{}",
+                sign,
+                whole,
+                fraction,
+                "0".repeat(x - AMOUNT_SCALE)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_with_scale(AMOUNT_SCALE))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = RuntimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_error = |reason: &str| {
+            RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(format!(
+                "Amount {:?} {}",
+                s, reason
+            )))
+        };
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(x) => (true, x),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(parse_error("is empty"));
+        }
+        if !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(parse_error("is not a decimal number"));
+        }
+        if fraction.len() > AMOUNT_SCALE {
+            return Err(parse_error("has more than four decimal places"));
+        }
+        let whole: i64 = match whole {
+            "" => 0,
+            x => x.parse().map_err(|_| parse_error("is too large"))?,
+        };
+        let fraction: i64 = format!("{:0<4}", fraction)
+            .parse()
+            .expect("Four ascii digits");
+        let units = whole
+            .checked_mul(UNITS_PER_WHOLE)
+            .and_then(|x| x.checked_add(fraction))
+            .ok_or_else(|| parse_error("is too large"))?;
+        Ok(Amount(if negative { -units } else { units }))
+    }
+}
+
+fn overflow_error(a: Amount, operation: &str, b: Amount) -> RuntimeError {
+    RuntimeError::Recoverable(RuntimeErrorType::AmountOverflow(format!(
+        "{} {} {}",
+        a, operation, b
+    )))
+}
+
+/// Amounts written as decimal strings in config files, like the csv input
+pub(crate) mod decimal {
+    use crate::amount::Amount;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&amount.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Amount::from_str(&s).map_err(de::Error::custom)
+    }
+
+    pub mod optional {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            amount: &Option<Amount>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match amount {
+                Some(x) => super::serialize(x, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Amount>, D::Error> {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_up_to_four_decimal_places() {
+        assert_eq!(Amount::from_str("1.5").unwrap(), Amount::from_units(15_000));
+        assert_eq!(Amount::from_str("0.0001").unwrap(), Amount::from_units(1));
+        assert_eq!(Amount::from_str("-2").unwrap(), Amount::from_units(-20_000));
+        assert!(Amount::from_str("1.000000001").is_err());
+        assert!(Amount::from_str("1e3").is_err());
+        assert!(Amount::from_str("").is_err());
+        assert!(Amount::from_str("922337203685478").is_err());
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        let max = Amount::from_units(i64::MAX);
+        assert!(max.try_add(Amount::from_units(1)).is_err());
+        assert!(Amount::from_units(i64::MIN)
+            .try_sub(Amount::from_units(1))
+            .is_err());
+        assert_eq!(
+            Amount::from_str("1.25")
+                .unwrap()
+                .try_sub(Amount::from_str("0.5").unwrap())
+                .unwrap(),
+            Amount::from_str("0.75").unwrap()
+        );
+    }
+
+    #[test]
+    fn renders_with_fixed_scale() {
+        let x = Amount::from_str("-12.3456").unwrap();
+        assert_eq!(x.to_string(), "-12.3456");
+        assert_eq!(x.to_string_with_scale(2), "-12.35");
+        assert_eq!(x.to_string_with_scale(6), "-12.345600");
+        assert_eq!(x.to_string_with_scale(0), "-12");
+        assert_eq!(Amount::ZERO.to_string_with_scale(0), "0");
+        // halves go away from zero, and what rounds to zero loses its sign
+        assert_eq!(
+            Amount::from_str("0.125").unwrap().to_string_with_scale(2),
+            "0.13"
+        );
+        assert_eq!(
+            Amount::from_str("-0.125").unwrap().to_string_with_scale(2),
+            "-0.13"
+        );
+        assert_eq!(
+            Amount::from_str("9.9995").unwrap().to_string_with_scale(3),
+            "10.000"
+        );
+        assert_eq!(
+            Amount::from_str("-0.004").unwrap().to_string_with_scale(2),
+            "0.00"
+        );
+    }
+}
@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+/// Admin change to the status of an account
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, strum::Display, strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AdminAction {
+    /// Lifts the lock a chargeback put on the account
+    Unlock,
+    /// Rejects every later transaction of the account until it is unfrozen
+    Freeze,
+    Unfreeze,
+}
+
+/// Kept on the account for every status change, whether it came from an admin row or
+/// from the `ErrorPolicy`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// tx of the row that made the change
+    pub tx_id: u32,
+    pub action: AdminAction,
+    pub reason: String,
+}
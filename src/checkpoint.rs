@@ -0,0 +1,77 @@
+use crate::client_account::ClientAccount;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::ledger::Ledger;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+pub(crate) const CHECKPOINT_FILE_NAME: &str = "checkpoint.json";
+
+/// Everything needed to continue a run after the last fully applied batch.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Checkpoint {
+    // position of the first record that was not applied yet
+    pub(crate) input_byte: u64,
+    pub(crate) input_line: u64,
+    pub(crate) input_record: u64,
+    pub(crate) store_marker: u64,
+    pub(crate) accounts: Vec<ClientAccount>,
+    #[serde(default)]
+    pub(crate) clock: Option<u64>, // latest timestamp read
+    #[serde(default)]
+    pub(crate) ledger: Ledger,
+    #[serde(default)]
+    pub(crate) history_offset: Option<u64>, // end of the history written up to here
+    #[serde(default)]
+    pub(crate) rejections_offset: Option<u64>, // end of the rejection report up to here
+    #[serde(default)]
+    pub(crate) journal_offset: Option<u64>, // end of the journal up to here
+}
+
+impl Checkpoint {
+    pub(crate) fn position(&self) -> csv::Position {
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.input_byte)
+            .set_line(self.input_line)
+            .set_record(self.input_record);
+        position
+    }
+
+    /// Writes aside and renames so an interrupted write leaves the previous checkpoint intact
+    pub(crate) async fn write(&self, path: &Path) -> Result<(), RuntimeError> {
+        let contents = serde_json::to_vec(self).map_err(checkpoint_error)?;
+        let temp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&temp_path)
+            .await
+            .map_err(checkpoint_error)?;
+        file.write_all(&contents).await.map_err(checkpoint_error)?;
+        file.sync_all().await.map_err(checkpoint_error)?;
+        tokio::fs::rename(&temp_path, path)
+            .await
+            .map_err(checkpoint_error)
+    }
+
+    pub(crate) async fn read(path: &Path) -> Result<Option<Self>, RuntimeError> {
+        let contents = match tokio::fs::read(path).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(checkpoint_error(e)),
+        };
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(checkpoint_error)
+    }
+
+    pub(crate) async fn remove(path: &Path) -> Result<(), RuntimeError> {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(checkpoint_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn checkpoint_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CheckpointError(e.to_string()))
+}
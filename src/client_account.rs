@@ -1,222 +1,698 @@
-use crate::constants::TEMP_DIRECTORY_LOC;
+use crate::amount::Amount;
+use crate::audit::{AdminAction, AuditRecord};
+use crate::currency::Currency;
 use crate::error::RuntimeErrorType::BalanceIssues;
 use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::fee::FeeSchedule;
+use crate::ledger::{JournalEntry, LedgerAccount};
+use crate::limits::{Velocity, WithdrawalLimits};
+use crate::policy::{DisputePolicy, DisputeRules, FeePolicy};
+use crate::store::{
+    DisputeEvent, SerializableTransaction, SerializableTransactionType, TransactionStore,
+    Transition,
+};
 use crate::transaction::{
-    CSVTransactionType, ChargeBackRequest, DepositRequest, DisputeRequest, ResolveRequest, State1,
-    WithdrawalRequest,
+    CSVTransactionType, ChargeBackRequest, DepositRequest, DisputeRequest, FreezeRequest,
+    ResolveRequest, TransferRequest, UnfreezeRequest, UnlockRequest, WithdrawalRequest,
 };
-use bigdecimal::BigDecimal;
-use serde::Deserialize;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Funds of one client in one currency
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: Amount,
+    pub held: Amount,
+}
+
+impl Balance {
+    /// `available + held`, failing with `AmountOverflow` if that doesn't fit an `Amount`
+    pub fn total(&self) -> Result<Amount, RuntimeError> {
+        self.available.try_add(self.held)
+    }
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientAccount {
     pub(crate) id: u16,
-    pub(crate) available: BigDecimal,
-    pub(crate) held: BigDecimal,
+    pub(crate) balances: BTreeMap<Currency, Balance>, // only currencies the client used
     pub(crate) locked: bool,
+    #[serde(default)]
+    pub(crate) frozen: bool, // set by a freeze row or when a failure froze the client, see ErrorPolicy
+    #[serde(default)]
+    pub(crate) audit: Vec<AuditRecord>,
+    #[serde(default)]
+    pub(crate) fees: BTreeMap<Currency, Amount>, // fee ledger, the fee of each tx is in the store
+    #[serde(default)]
+    pub(crate) velocity: Velocity,
+    #[serde(skip)]
+    pub(crate) journal: Vec<JournalEntry>, // posted since the last drain, see `take_journal`
 }
 
 impl ClientAccount {
     pub fn new_account(id: u16) -> Self {
         ClientAccount {
             id,
-            available: BigDecimal::from(0),
-            held: BigDecimal::from(0),
+            balances: BTreeMap::new(),
             locked: false,
+            frozen: false,
+            audit: vec![],
+            fees: BTreeMap::new(),
+            velocity: Velocity::default(),
+            journal: vec![],
         }
     }
 
-    /// Takes a state 1 transaction and writes it
-    pub(crate) async fn execute_deposit(&mut self, r: DepositRequest) -> Result<(), RuntimeError> {
+    /// Nothing ever happened to the account: no balance, fee, lock, freeze or audit record
+    pub(crate) fn is_untouched(&self) -> bool {
+        self.balances.is_empty()
+            && self.fees.is_empty()
+            && self.audit.is_empty()
+            && !self.locked
+            && !self.frozen
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Available funds in the default currency
+    pub fn available(&self) -> Amount {
+        self.balance(Currency::default()).available
+    }
+
+    /// Held funds in the default currency
+    pub fn held(&self) -> Amount {
+        self.balance(Currency::default()).held
+    }
+
+    /// `available + held` in the default currency, failing with `AmountOverflow` if that
+    /// doesn't fit an `Amount`
+    pub fn total(&self) -> Result<Amount, RuntimeError> {
+        self.balance(Currency::default()).total()
+    }
+
+    /// Funds in `currency`, zero if the client never used it
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// Every currency the client used with its funds, sorted by currency
+    pub fn balances(&self) -> impl Iterator<Item = (Currency, Balance)> + '_ {
+        self.balances.iter().map(|(c, b)| (*c, *b))
+    }
+
+    fn set_balance(&mut self, currency: Currency, balance: Balance) {
+        self.balances.insert(currency, balance);
+    }
+
+    /// Fees charged in `currency` so far, less the ones a chargeback refunded
+    pub fn fees(&self, currency: Currency) -> Amount {
+        self.fees.get(&currency).copied().unwrap_or_default()
+    }
+
+    fn set_fees(&mut self, currency: Currency, fees: Amount) {
+        self.fees.insert(currency, fees);
+    }
+
+    /// Set by a chargeback, every later transaction of the client is rejected
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Set by a freeze row, or when a failure froze the client under `ErrorPolicy::Freeze`
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Every unlock, freeze and unfreeze of the account, oldest first
+    pub fn audit(&self) -> &[AuditRecord] {
+        &self.audit
+    }
+
+    /// Journal entries of every transaction applied since the last call. Entries of a
+    /// transaction touching two clients are all posted on the account that applied it.
+    pub fn take_journal(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// Records that `amount` moved from `credit` to `debit`, nothing if it is zero
+    fn post(
+        &mut self,
+        s: &SerializableTransaction,
+        transaction_type: CSVTransactionType,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        amount: Amount,
+    ) {
+        if amount != Amount::ZERO {
+            self.journal.push(JournalEntry {
+                tx_id: s.tx_id,
+                transaction_type,
+                currency: s.currency,
+                debit,
+                credit,
+                amount,
+            });
+        }
+    }
+
+    /// Takes a state 1 transaction and writes it as processed, or as rejected if it
+    /// would overflow the balance. The fee `fees` asks for is taken from the deposit.
+    pub(crate) async fn execute_deposit(
+        &mut self,
+        store: &dyn TransactionStore,
+        r: DepositRequest,
+        fees: &FeeSchedule,
+    ) -> Result<(), RuntimeError> {
         self.ensure_unlocked()?;
-        let result = SerializableTransaction::new_from_state1(&r)?;
-        if SerializableTransaction::read(r.0.tx_id).await.is_ok() {
-            return Err(RuntimeError::Recoverable(
-                RuntimeErrorType::TransactionAlreadyPresent,
-            ));
+        let mut result = SerializableTransaction::new_from_state1(&r)?;
+        result.fee = fees.deposit_fee(result.amount)?;
+        let currency = result.currency;
+        let charged = self.fees(currency).try_add(result.fee)?;
+        // the fee comes out of the deposit, never out of what the account already had
+        if result.fee > result.amount {
+            store.put(result.rejected()).await?;
+            let err_string = format!(
+                "Deposit {} of account {} doesn't cover its fee",
+                r.0.tx_id, self.id
+            );
+            return Err(RuntimeError::Recoverable(BalanceIssues(err_string)));
         }
-        result.overwrite_state().await?;
-        self.available +=
-            &r.0.amount
-                .expect("Deposit Request makes sure this is there");
+        let mut balance = self.balance(currency);
+        let available = match balance
+            .available
+            .try_add(result.amount)
+            .and_then(|x| x.try_sub(result.fee))
+        {
+            Ok(x) => x,
+            Err(e) => {
+                store.put(result.rejected()).await?;
+                return Err(e);
+            }
+        };
+        store.put(result.clone()).await?;
+        let available_account = LedgerAccount::ClientAvailable(self.id);
+        let deposit = CSVTransactionType::Deposit;
+        self.post(
+            &result,
+            deposit,
+            LedgerAccount::ExternalCash,
+            available_account,
+            result.amount,
+        );
+        self.post(
+            &result,
+            deposit,
+            available_account,
+            LedgerAccount::FeeIncome,
+            result.fee,
+        );
+        balance.available = available;
+        self.set_balance(currency, balance);
+        self.set_fees(currency, charged);
         Ok(())
     }
 
-    /// Takes a state 1 transaction and writes it
+    /// Takes a state 1 transaction and writes it as processed, or as rejected if the
+    /// balance doesn't cover it and the fee `fees` asks for on top, or it breaks `limits`
     pub(crate) async fn execute_withdrawal(
         &mut self,
+        store: &dyn TransactionStore,
         r: WithdrawalRequest,
+        fees: &FeeSchedule,
+        limits: WithdrawalLimits,
     ) -> Result<(), RuntimeError> {
         self.ensure_unlocked()?;
-        self.ensure_balance(
-            r.0.amount
-                .as_ref()
-                .expect("Withdrawal wrapper makes sure amount is present and is positive"),
-        )
-        .map_err(|e| {
+        let mut result = SerializableTransaction::new_from_state1(&r)?;
+        result.fee = fees.withdrawal_fee(result.amount)?;
+        let currency = result.currency;
+        let charged = self.fees(currency).try_add(result.fee)?;
+        let amount = result.amount.try_add(result.fee)?;
+        if let Err(e) = self.ensure_balance(currency, amount) {
+            // kept as rejected, so its tx_id stays taken and reports show how it ended
+            store.put(result.rejected()).await?;
             // Make sure to ignore balance issues here
-            if let RuntimeError::NonRecoverable(e) = e {
-                RuntimeError::Recoverable(e)
-            } else {
-                e
-            }
-        })?;
-        if SerializableTransaction::read(r.0.tx_id).await.is_ok() {
-            return Err(RuntimeError::Recoverable(
-                RuntimeErrorType::TransactionAlreadyPresent,
-            ));
+            return Err(match e {
+                RuntimeError::NonRecoverable(e) => RuntimeError::Recoverable(e),
+                e => e,
+            });
         }
-        let result = SerializableTransaction::new_from_state1(&r)?;
-        result.overwrite_state().await?;
+        let mut balance = self.balance(currency);
+        balance.available = balance.available.try_sub(amount)?;
+        if let Err(e) = self
+            .velocity
+            .check(&limits, currency, result.amount, balance.available)
+        {
+            store.put(result.rejected()).await?;
+            return Err(e);
+        }
+        let withdrawn = result.amount;
+        store.put(result.clone()).await?;
+        let available_account = LedgerAccount::ClientAvailable(self.id);
+        let withdrawal = CSVTransactionType::Withdrawal;
+        self.post(
+            &result,
+            withdrawal,
+            available_account,
+            LedgerAccount::ExternalCash,
+            withdrawn,
+        );
+        self.post(
+            &result,
+            withdrawal,
+            available_account,
+            LedgerAccount::FeeIncome,
+            result.fee,
+        );
 
-        self.available -=
-            r.0.amount
-                .as_ref()
-                .expect("Withdrawal wrapper makes sure amount is present and is positive");
+        self.set_balance(currency, balance);
+        self.set_fees(currency, charged);
+        self.velocity.record(&limits, currency, withdrawn);
         Ok(())
     }
 
-    ///Finds a state1 transaction in file
-    /// Converts it into state 2
-    pub(crate) async fn execute_dispute(&mut self, r: DisputeRequest) -> Result<(), RuntimeError> {
+    /// Moves the amount from this account to `destination` in one step. Nothing changes
+    /// unless both sides can take it, a transfer the balance doesn't cover is stored as rejected.
+    pub(crate) async fn execute_transfer(
+        &mut self,
+        destination: &mut ClientAccount,
+        store: &dyn TransactionStore,
+        r: TransferRequest,
+    ) -> Result<(), RuntimeError> {
+        if destination.id != r.destination() {
+            return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+                format!(
+                    "Transfer to {} given account {}",
+                    r.destination(),
+                    destination.id
+                ),
+            )));
+        }
         self.ensure_unlocked()?;
-        let s = match SerializableTransaction::read(r.0.tx_id).await {
+        destination.ensure_unlocked()?;
+        let result = SerializableTransaction::new_from_state1(&r)?;
+        let currency = result.currency;
+        if let Err(e) = self.ensure_balance(currency, result.amount) {
+            store.put(result.rejected()).await?;
+            // a transfer above balance is ignored like a withdrawal
+            return Err(match e {
+                RuntimeError::NonRecoverable(e) => RuntimeError::Recoverable(e),
+                e => e,
+            });
+        }
+        let mut credited = destination.balance(currency);
+        credited.available = match credited.available.try_add(result.amount) {
             Ok(x) => x,
-            Err(_) => {
-                return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState("Transaction not present".to_string()),
-                ));
+            Err(e) => {
+                store.put(result.rejected()).await?;
+                return Err(e);
             }
         };
+        let mut debited = self.balance(currency);
+        debited.available = debited.available.try_sub(result.amount)?;
+        store.put(result.clone()).await?;
+        self.post(
+            &result,
+            CSVTransactionType::Transfer,
+            LedgerAccount::ClientAvailable(self.id),
+            LedgerAccount::ClientAvailable(destination.id),
+            result.amount,
+        );
+        self.set_balance(currency, debited);
+        destination.set_balance(currency, credited);
+        Ok(())
+    }
+
+    ///Finds a processed or resolved transaction in the store
+    /// Moves it to disputed. What a disputed withdrawal does depends on `rules.policy`,
+    /// a resolved transaction can be disputed again until it reaches `rules.max_cycles`.
+    /// A dispute coming more than `rules.window` after the transaction is rejected.
+    pub(crate) async fn execute_dispute(
+        &mut self,
+        store: &dyn TransactionStore,
+        r: DisputeRequest,
+        rules: DisputeRules,
+    ) -> Result<(), RuntimeError> {
+        self.ensure_open_for_disputes(rules)?;
+        let s = Self::read_transaction(store, r.0.tx_id).await?;
 
-        match s.state {
-            SerializableState::State1 => {}
-            _ => {
+        let transition = self.ensure_allowed(&s, DisputeEvent::Dispute)?;
+        if s.dispute_count() >= rules.max_cycles as usize {
+            return Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(format!(
+                    "Transaction {} was already disputed {} times",
+                    s.tx_id,
+                    s.dispute_count()
+                )),
+            ));
+        }
+        if let (Some(window), Some(at), Some(timestamp)) =
+            (rules.window, r.0.timestamp, s.timestamp)
+        {
+            if at.saturating_sub(timestamp) > window {
+                let err_string = format!(
+                    "Dispute of transaction {} came {} seconds after it, the window is {}",
+                    s.tx_id,
+                    at - timestamp,
+                    window
+                );
                 return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState(
-                        "Transaction in wrong state".to_string(),
-                    ),
+                    RuntimeErrorType::DisputeRejected(err_string),
                 ));
             }
         }
 
-        let s = s.upgrade_state();
-        s.overwrite_state().await?;
-
-        match s.transaction_type {
-            SerializableTransactionType::Deposit => {
-                self.ensure_balance(&s.amount)?;
-                self.available -= &s.amount;
-                self.held += &s.amount;
+        // worked out before the state changes so a failure leaves store and balances in step
+        let balance = self.balance(s.currency);
+        let (available, held) = match (s.transaction_type, rules.policy) {
+            (SerializableTransactionType::Deposit, _)
+            | (SerializableTransactionType::Transfer, _) => {
+                self.ensure_balance(s.currency, s.amount)?;
+                (
+                    balance.available.try_sub(s.amount)?,
+                    balance.held.try_add(s.amount)?,
+                )
             }
-            SerializableTransactionType::Withdrawal => {
-                self.held += &s.amount;
-            }
-        }
-        Ok(())
-    }
-
-    ///Finds a state2 transaction in file
-    ///Writes it back to state 3
-    pub(crate) async fn execute_resolve(&mut self, r: ResolveRequest) -> Result<(), RuntimeError> {
-        self.ensure_unlocked()?;
-        let s = match SerializableTransaction::read(r.0.tx_id).await {
-            Ok(x) => x,
-            Err(_) => {
+            (SerializableTransactionType::Withdrawal, DisputePolicy::DepositsOnly) => {
                 return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState("Transaction not present".to_string()),
+                    RuntimeErrorType::WrongTransactionState(
+                        "Only deposits can be disputed".to_string(),
+                    ),
                 ));
             }
-        };
-
-        match s.state {
-            SerializableState::State2 => {}
-            _ => {
+            (SerializableTransactionType::Withdrawal, DisputePolicy::RejectWithdrawals) => {
+                let err_string = format!("Account {} disputed withdrawal {}", self.id, s.tx_id);
                 return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState(
-                        "Transaction in wrong state".to_string(),
-                    ),
+                    RuntimeErrorType::DisputeRejected(err_string),
                 ));
             }
-        }
-
-        let s = s.upgrade_state();
-        s.overwrite_state().await?;
-        match s.transaction_type {
-            SerializableTransactionType::Deposit => {
-                self.available += &s.amount;
-                self.ensure_hold_balance(&s.amount)?;
-                self.held -= &s.amount;
+            // the claimed amount is held until the dispute is settled
+            (SerializableTransactionType::Withdrawal, DisputePolicy::WithdrawalReversal) => {
+                (balance.available, balance.held.try_add(s.amount)?)
             }
-            SerializableTransactionType::Withdrawal => {
-                self.available += &s.amount;
-                self.ensure_hold_balance(&s.amount)?;
-                self.held -= &s.amount;
+        };
+        store.transition(s.tx_id, transition, r.0.timestamp).await?;
+
+        self.set_balance(s.currency, Balance { available, held });
+        let held_account = LedgerAccount::ClientHeld(self.id);
+        let source = self.held_from(&s);
+        self.post(
+            &s,
+            CSVTransactionType::Dispute,
+            source,
+            held_account,
+            s.amount,
+        );
+        Ok(())
+    }
+
+    ///Finds a disputed transaction in the store
+    ///Moves it to resolved, where it can be disputed again
+    pub(crate) async fn execute_resolve(
+        &mut self,
+        store: &dyn TransactionStore,
+        r: ResolveRequest,
+        rules: DisputeRules,
+    ) -> Result<(), RuntimeError> {
+        self.ensure_open_for_disputes(rules)?;
+        let s = Self::read_transaction(store, r.0.tx_id).await?;
+
+        let transition = self.ensure_allowed(&s, DisputeEvent::Resolve)?;
+
+        self.ensure_hold_balance(s.currency, s.amount)?;
+        let balance = self.balance(s.currency);
+        let available = match s.transaction_type {
+            SerializableTransactionType::Deposit | SerializableTransactionType::Transfer => {
+                balance.available.try_add(s.amount)?
             }
-        }
+            // the withdrawal stands, only the claim is dropped
+            SerializableTransactionType::Withdrawal => balance.available,
+        };
+        let held = balance.held.try_sub(s.amount)?;
+        store.transition(s.tx_id, transition, r.0.timestamp).await?;
+        self.set_balance(s.currency, Balance { available, held });
+        let held_account = LedgerAccount::ClientHeld(self.id);
+        let source = self.held_from(&s);
+        self.post(
+            &s,
+            CSVTransactionType::Resolve,
+            held_account,
+            source,
+            s.amount,
+        );
 
         Ok(())
     }
 
-    ///Finds a state2 transaction in file
-    ///Writes it back to state 3
+    ///Finds a disputed transaction in the store
+    ///Moves it to charged back. Transfers need `execute_transfer_chargeback`.
+    ///The fee of the transaction is refunded if `rules.fee_policy` says so.
     pub(crate) async fn execute_chargeback(
         &mut self,
+        store: &dyn TransactionStore,
         r: ChargeBackRequest,
+        rules: DisputeRules,
     ) -> Result<(), RuntimeError> {
-        self.ensure_unlocked()?;
-        let s = match SerializableTransaction::read(r.0.tx_id).await {
-            Ok(x) => x,
-            Err(_) => {
-                return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState("Transaction not present".to_string()),
-                ));
+        self.chargeback(None, store, r, rules).await
+    }
+
+    /// Chargeback of a transfer this account received. The amount goes back to `source`,
+    /// the client the transfer came from.
+    pub(crate) async fn execute_transfer_chargeback(
+        &mut self,
+        source: &mut ClientAccount,
+        store: &dyn TransactionStore,
+        r: ChargeBackRequest,
+        rules: DisputeRules,
+    ) -> Result<(), RuntimeError> {
+        self.chargeback(Some(source), store, r, rules).await
+    }
+
+    async fn chargeback(
+        &mut self,
+        source: Option<&mut ClientAccount>,
+        store: &dyn TransactionStore,
+        r: ChargeBackRequest,
+        rules: DisputeRules,
+    ) -> Result<(), RuntimeError> {
+        self.ensure_open_for_disputes(rules)?;
+        let s = Self::read_transaction(store, r.0.tx_id).await?;
+        let transition = self.ensure_allowed(&s, DisputeEvent::Chargeback)?;
+        self.ensure_hold_balance(s.currency, s.amount)?;
+        let balance = self.balance(s.currency);
+        let available = match s.transaction_type {
+            SerializableTransactionType::Deposit | SerializableTransactionType::Transfer => {
+                balance.available
             }
+            // the withdrawal is reversed
+            SerializableTransactionType::Withdrawal => balance.available.try_add(s.amount)?,
         };
-        match s.state {
-            SerializableState::State2 => {}
-            _ => {
-                return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState(
-                        "Transaction in wrong state".to_string(),
-                    ),
+        let (available, fees) = match rules.fee_policy {
+            FeePolicy::Keep => (available, self.fees(s.currency)),
+            FeePolicy::Refund => (
+                available.try_add(s.fee)?,
+                self.fees(s.currency).try_sub(s.fee)?,
+            ),
+        };
+        let held = balance.held.try_sub(s.amount)?;
+        let refund = match (s.transaction_type, source) {
+            (SerializableTransactionType::Transfer, Some(x)) if x.id == s.client_id => {
+                let mut refunded = x.balance(s.currency);
+                refunded.available = refunded.available.try_add(s.amount)?;
+                Some((x, refunded))
+            }
+            (SerializableTransactionType::Transfer, _) => {
+                let err_string = format!(
+                    "Chargeback of transfer {} needs the account of client {}",
+                    s.tx_id, s.client_id
+                );
+                return Err(RuntimeError::NonRecoverable(
+                    RuntimeErrorType::WrongTransactionState(err_string),
                 ));
             }
+            _ => None,
+        };
+        store.transition(s.tx_id, transition, r.0.timestamp).await?;
+        // where the held amount ends up
+        let destination = match (s.transaction_type, &refund) {
+            (SerializableTransactionType::Deposit, _) => LedgerAccount::ExternalCash,
+            (_, Some((source, _))) => LedgerAccount::ClientAvailable(source.id),
+            _ => LedgerAccount::ClientAvailable(self.id),
+        };
+        if let Some((source, refunded)) = refund {
+            source.set_balance(s.currency, refunded);
         }
-        let s = s.upgrade_state();
-        s.overwrite_state().await?;
-        match s.transaction_type {
-            SerializableTransactionType::Deposit | SerializableTransactionType::Withdrawal => {
-                self.ensure_hold_balance(&s.amount)?;
-                self.held -= &s.amount;
-                self.locked = true;
-            }
+        self.set_balance(s.currency, Balance { available, held });
+        self.set_fees(s.currency, fees);
+        let chargeback = CSVTransactionType::Chargeback;
+        let held_account = LedgerAccount::ClientHeld(self.id);
+        self.post(&s, chargeback, held_account, destination, s.amount);
+        if rules.fee_policy == FeePolicy::Refund {
+            let available_account = LedgerAccount::ClientAvailable(self.id);
+            self.post(
+                &s,
+                chargeback,
+                LedgerAccount::FeeIncome,
+                available_account,
+                s.fee,
+            );
+        }
+        self.locked = true;
+        Ok(())
+    }
+
+    /// Lifts the lock a chargeback put on the account
+    pub fn execute_unlock(&mut self, r: UnlockRequest) -> Result<(), RuntimeError> {
+        if !self.locked {
+            return Err(self.wrong_account_state("is not locked"));
+        }
+        self.locked = false;
+        self.record(
+            r.0.tx_id,
+            AdminAction::Unlock,
+            r.0.reason.unwrap_or_default(),
+        );
+        Ok(())
+    }
+
+    /// Rejects every later transaction of the account until it is unfrozen
+    pub fn execute_freeze(&mut self, r: FreezeRequest) -> Result<(), RuntimeError> {
+        self.freeze(r.0.tx_id, r.0.reason.unwrap_or_default())
+    }
+
+    pub fn execute_unfreeze(&mut self, r: UnfreezeRequest) -> Result<(), RuntimeError> {
+        if !self.frozen {
+            return Err(self.wrong_account_state("is not frozen"));
+        }
+        self.frozen = false;
+        self.record(
+            r.0.tx_id,
+            AdminAction::Unfreeze,
+            r.0.reason.unwrap_or_default(),
+        );
+        Ok(())
+    }
+
+    /// Freezes the account and audits it, `tx_id` being the row that caused it
+    pub(crate) fn freeze(&mut self, tx_id: u32, reason: String) -> Result<(), RuntimeError> {
+        if self.frozen {
+            return Err(self.wrong_account_state("is already frozen"));
         }
+        self.frozen = true;
+        self.record(tx_id, AdminAction::Freeze, reason);
         Ok(())
     }
 
-    fn ensure_balance(&self, amount: &BigDecimal) -> Result<(), RuntimeError> {
-        match &self.available >= amount {
+    fn record(&mut self, tx_id: u32, action: AdminAction, reason: String) {
+        log::info!(
+            "{} of client {} by tx {}: {}",
+            action,
+            self.id,
+            tx_id,
+            reason
+        );
+        self.audit.push(AuditRecord {
+            tx_id,
+            action,
+            reason,
+        });
+    }
+
+    fn wrong_account_state(&self, problem: &str) -> RuntimeError {
+        RuntimeError::Recoverable(RuntimeErrorType::WrongAccountState(format!(
+            "Account {} {}",
+            self.id, problem
+        )))
+    }
+
+    /// Ledger account a dispute of `s` moves the amount to held from. A disputed
+    /// withdrawal is money the client claims back, so it is booked as a possible loss.
+    fn held_from(&self, s: &SerializableTransaction) -> LedgerAccount {
+        match s.transaction_type {
+            SerializableTransactionType::Withdrawal => LedgerAccount::ChargebackLoss,
+            _ => LedgerAccount::ClientAvailable(self.id),
+        }
+    }
+
+    /// The `Transition` `event` makes on `s`, checked along with who may apply it before
+    /// any balance is worked out
+    fn ensure_allowed(
+        &self,
+        s: &SerializableTransaction,
+        event: DisputeEvent,
+    ) -> Result<Transition, RuntimeError> {
+        if s.transaction_type == SerializableTransactionType::Transfer
+            && s.destination != Some(self.id)
+        {
+            return Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(format!(
+                    "Transfer {} is disputed by the client it went to",
+                    s.tx_id
+                )),
+            ));
+        }
+        if s.transaction_type != SerializableTransactionType::Transfer && s.client_id != self.id {
+            return Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(format!(
+                    "Transaction {} belongs to client {}",
+                    s.tx_id, s.client_id
+                )),
+            ));
+        }
+        Transition::new(s.state(), event).ok_or_else(|| {
+            RuntimeError::Recoverable(RuntimeErrorType::WrongTransactionState(format!(
+                "Transaction in wrong state for a {}: {}",
+                event,
+                s.state()
+            )))
+        })
+    }
+
+    async fn read_transaction(
+        store: &dyn TransactionStore,
+        tx_id: u32,
+    ) -> Result<SerializableTransaction, RuntimeError> {
+        match store.get(tx_id).await? {
+            Some(x) => Ok(x),
+            None => Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState("Transaction not present".to_string()),
+            )),
+        }
+    }
+
+    fn ensure_balance(&self, currency: Currency, amount: Amount) -> Result<(), RuntimeError> {
+        let available = self.balance(currency).available;
+        match available >= amount {
             true => Ok(()),
             false => {
-                let err_string = format!("Given chain of transactions is erroneous account: {:?} required balance {:?} but found {:?}", &self.id, &amount, &self.available);
+                let err_string = format!("Given chain of transactions is erroneous account: {} required balance {} {} but found {}", &self.id, &amount, currency, &available);
                 Err(RuntimeError::NonRecoverable(BalanceIssues(err_string)))
             }
         }
     }
 
-    fn ensure_hold_balance(&self, amount: &BigDecimal) -> Result<(), RuntimeError> {
-        match &self.held >= amount {
+    fn ensure_hold_balance(&self, currency: Currency, amount: Amount) -> Result<(), RuntimeError> {
+        let held = self.balance(currency).held;
+        match held >= amount {
             true => Ok(()),
             false => {
-                let err_string = format!("Given chain of transactions is erroneous account: {:?} required held balance {:?} but found {:?}", &self.id, &amount, &self.held);
+                let err_string = format!("Given chain of transactions is erroneous account: {} required held balance {} {} but found {}", &self.id, &amount, currency, &held);
                 Err(RuntimeError::NonRecoverable(BalanceIssues(err_string)))
             }
         }
     }
 
-    fn ensure_unlocked(&self) -> Result<(), RuntimeError> {
+    /// Disputes, resolves and chargebacks go through on a frozen account if
+    /// `rules.frozen_accounts` allows them
+    fn ensure_open_for_disputes(&self, rules: DisputeRules) -> Result<(), RuntimeError> {
+        match self.frozen && rules.frozen_accounts {
+            true => self.ensure_not_locked(),
+            false => self.ensure_unlocked(),
+        }
+    }
+
+    fn ensure_not_locked(&self) -> Result<(), RuntimeError> {
         if self.locked {
             let err_string = format!("Account is locked {}", self.id);
             return Err(RuntimeError::Recoverable(RuntimeErrorType::LockedAccount(
@@ -225,128 +701,397 @@ impl ClientAccount {
         }
         Ok(())
     }
-}
-
-#[derive(
-    Copy,
-    Clone,
-    Debug,
-    Eq,
-    PartialEq,
-    Hash,
-    serde::Serialize,
-    serde::Deserialize,
-    strum::Display,
-    strum::EnumIter,
-    strum::EnumString,
-    strum::EnumVariantNames,
-    strum::IntoStaticStr,
-)]
-enum SerializableTransactionType {
-    Deposit,
-    Withdrawal,
-}
 
-#[derive(
-    Copy,
-    Clone,
-    Debug,
-    Eq,
-    PartialEq,
-    Hash,
-    serde::Serialize,
-    serde::Deserialize,
-    strum::Display,
-    strum::EnumIter,
-    strum::EnumString,
-    strum::EnumVariantNames,
-    strum::IntoStaticStr,
-)]
-enum SerializableState {
-    State1, // Deposit or Withdrawal
-    State2, // Dispute
-    State3, // Resolve or Chargeback
+    fn ensure_unlocked(&self) -> Result<(), RuntimeError> {
+        self.ensure_not_locked()?;
+        if self.frozen {
+            let err_string = format!("Account is frozen {}", self.id);
+            return Err(RuntimeError::Recoverable(RuntimeErrorType::FrozenAccount(
+                err_string,
+            )));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct SerializableTransaction {
-    amount: BigDecimal,
-    client_id: u16,
-    tx_id: u32,
-    transaction_type: SerializableTransactionType,
-    state: SerializableState,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryTransactionStore, SerializableState};
+    use crate::transaction::{CSVTransaction, CSVTransactionType};
+    use std::convert::TryInto;
 
-impl SerializableTransaction {
-    pub fn new_from_state1(transaction: &dyn State1) -> Result<Self, RuntimeError> {
-        let csv_transaction = transaction.inner();
-        let transaction_type = match csv_transaction.transaction_type {
-            CSVTransactionType::Deposit => SerializableTransactionType::Deposit,
-            CSVTransactionType::Withdrawal => SerializableTransactionType::Withdrawal,
-            _ => {
-                return Err(RuntimeError::Recoverable(
-                    RuntimeErrorType::WrongTransactionState(
-                        "Ignoring this transaction".to_string(),
-                    ),
-                ))
-            }
-        };
-        Ok(Self {
-            amount: csv_transaction
-                .amount
-                .clone()
-                .expect("State 1 transactions have an amount"),
-            client_id: csv_transaction.client_id,
-            tx_id: csv_transaction.tx_id,
+    fn csv_transaction(
+        transaction_type: CSVTransactionType,
+        tx_id: u32,
+        amount: Option<i32>,
+    ) -> CSVTransaction {
+        CSVTransaction {
+            amount: amount.map(|x| Amount::from_units(x as i64 * 10_000)),
+            client_id: 1,
+            tx_id,
             transaction_type,
-            state: SerializableState::State1,
-        })
+            reason: None,
+            destination: None,
+            currency: Currency::default(),
+            timestamp: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn dispute_then_resolve_restores_balance() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = ClientAccount::new_account(1);
+        account
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+
+        account
+            .execute_dispute(
+                &store,
+                csv_transaction(CSVTransactionType::Dispute, 1, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await?;
+        assert_eq!(account.available(), Amount::ZERO);
+        assert_eq!(account.held(), Amount::from_units(100_000));
+
+        account
+            .execute_resolve(
+                &store,
+                csv_transaction(CSVTransactionType::Resolve, 1, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await?;
+        assert_eq!(account.available(), Amount::from_units(100_000));
+        assert_eq!(account.held(), Amount::ZERO);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disputes_of_other_clients_transactions_are_rejected() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut owner = ClientAccount::new_account(1);
+        owner
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+
+        let mut other = ClientAccount::new_account(2);
+        let mut dispute = csv_transaction(CSVTransactionType::Dispute, 1, None);
+        dispute.client_id = 2;
+        let result = other
+            .execute_dispute(&store, dispute.try_into()?, DisputeRules::default())
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(_)
+            ))
+        ));
+        assert_eq!(other.held(), Amount::ZERO);
+        let stored = store.get(1).await?.expect("stored deposit");
+        assert_eq!(stored.state(), SerializableState::Processed);
+        Ok(())
     }
 
-    pub(crate) async fn overwrite_state(&self) -> Result<(), RuntimeError> {
-        let path = format!("{}{}", TEMP_DIRECTORY_LOC, self.tx_id);
-        let _ = tokio::fs::remove_file(path.clone()).await.map_err(|_| {
-            RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(
-                "Remove file failed".to_string(),
+    #[tokio::test]
+    async fn resolve_without_dispute_is_rejected() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = ClientAccount::new_account(1);
+        account
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+
+        let result = account
+            .execute_resolve(
+                &store,
+                csv_transaction(CSVTransactionType::Resolve, 1, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(_)
             ))
-        });
-        let contents = {
-            match serde_json::to_string(&self) {
-                Ok(x) => x,
-                Err(e) => {
-                    return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
-                        e.to_string(),
-                    )));
-                }
-            }
+        ));
+        assert_eq!(account.available(), Amount::from_units(100_000));
+        Ok(())
+    }
+
+    /// Deposits 10 as tx 1 and withdraws 4 as tx 2
+    async fn deposit_then_withdraw(
+        store: &dyn TransactionStore,
+    ) -> Result<ClientAccount, RuntimeError> {
+        let mut account = ClientAccount::new_account(1);
+        account
+            .execute_deposit(
+                store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+        account
+            .execute_withdrawal(
+                store,
+                csv_transaction(CSVTransactionType::Withdrawal, 2, Some(4)).try_into()?,
+                &FeeSchedule::default(),
+                WithdrawalLimits::default(),
+            )
+            .await?;
+        Ok(account)
+    }
+
+    async fn dispute_withdrawal(
+        account: &mut ClientAccount,
+        store: &dyn TransactionStore,
+        policy: DisputePolicy,
+    ) -> Result<(), RuntimeError> {
+        let rules = DisputeRules {
+            policy,
+            ..DisputeRules::default()
         };
-        let contents = contents.as_bytes();
-        tokio::fs::write(path, contents).await.map_err(|_| {
-            RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(
-                "Write to file failed".to_string(),
+        account
+            .execute_dispute(
+                store,
+                csv_transaction(CSVTransactionType::Dispute, 2, None).try_into()?,
+                rules,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn deposits_only_rejects_withdrawal_disputes() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = deposit_then_withdraw(&store).await?;
+        let result = dispute_withdrawal(&mut account, &store, DisputePolicy::DepositsOnly).await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(_)
             ))
-        })?;
+        ));
+        assert_eq!(account.available(), Amount::from_units(60_000));
+        assert_eq!(account.held(), Amount::ZERO);
         Ok(())
     }
 
-    pub(crate) async fn read(tx_id: u32) -> Result<Self, RuntimeError> {
-        let path = format!("{}{}", TEMP_DIRECTORY_LOC, tx_id);
-        let result = tokio::fs::read(path).await.map_err(|e| {
-            RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(e.to_string()))
-        })?;
+    #[tokio::test]
+    async fn reject_withdrawals_fails_withdrawal_disputes() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = deposit_then_withdraw(&store).await?;
         let result =
-            serde_json::from_str::<SerializableTransaction>(&*String::from_utf8(result).expect(""))
-                .expect("");
-        Ok(result)
+            dispute_withdrawal(&mut account, &store, DisputePolicy::RejectWithdrawals).await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::DisputeRejected(_)
+            ))
+        ));
+        assert_eq!(account.held(), Amount::ZERO);
+        let transaction = store.get(2).await?.expect("stored by the withdrawal");
+        assert_eq!(transaction.state(), SerializableState::Processed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdrawal_reversal_resolve_keeps_the_withdrawal() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = deposit_then_withdraw(&store).await?;
+        dispute_withdrawal(&mut account, &store, DisputePolicy::WithdrawalReversal).await?;
+        assert_eq!(account.available(), Amount::from_units(60_000));
+        assert_eq!(account.held(), Amount::from_units(40_000));
+
+        account
+            .execute_resolve(
+                &store,
+                csv_transaction(CSVTransactionType::Resolve, 2, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await?;
+        assert_eq!(account.available(), Amount::from_units(60_000));
+        assert_eq!(account.held(), Amount::ZERO);
+        assert!(!account.locked);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdrawal_reversal_chargeback_returns_the_amount() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = deposit_then_withdraw(&store).await?;
+        dispute_withdrawal(&mut account, &store, DisputePolicy::WithdrawalReversal).await?;
+
+        account
+            .execute_chargeback(
+                &store,
+                csv_transaction(CSVTransactionType::Chargeback, 2, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await?;
+        assert_eq!(account.available(), Amount::from_units(100_000));
+        assert_eq!(account.held(), Amount::ZERO);
+        assert!(account.locked);
+        Ok(())
+    }
+
+    async fn dispute_then_resolve(
+        account: &mut ClientAccount,
+        store: &dyn TransactionStore,
+        rules: DisputeRules,
+    ) -> Result<(), RuntimeError> {
+        account
+            .execute_dispute(
+                store,
+                csv_transaction(CSVTransactionType::Dispute, 1, None).try_into()?,
+                rules,
+            )
+            .await?;
+        account
+            .execute_resolve(
+                store,
+                csv_transaction(CSVTransactionType::Resolve, 1, None).try_into()?,
+                rules,
+            )
+            .await
     }
 
-    pub(crate) fn upgrade_state(self) -> Self {
-        let mut t = self.clone();
-        t.state = match self.state {
-            SerializableState::State1 => SerializableState::State2,
-            SerializableState::State2 => SerializableState::State3,
-            SerializableState::State3 => SerializableState::State3,
+    #[tokio::test]
+    async fn resolved_transactions_can_be_disputed_up_to_the_limit() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = ClientAccount::new_account(1);
+        account
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+        let rules = DisputeRules {
+            max_cycles: 2,
+            ..DisputeRules::default()
         };
-        t
+        dispute_then_resolve(&mut account, &store, rules).await?;
+        dispute_then_resolve(&mut account, &store, rules).await?;
+        let result = dispute_then_resolve(&mut account, &store, rules).await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(_)
+            ))
+        ));
+
+        let transaction = store.get(1).await?.expect("stored by the deposit");
+        assert_eq!(transaction.state(), SerializableState::Resolved);
+        assert_eq!(transaction.dispute_count(), 2);
+        assert_eq!(
+            transaction.history,
+            &[
+                DisputeEvent::Dispute,
+                DisputeEvent::Resolve,
+                DisputeEvent::Dispute,
+                DisputeEvent::Resolve,
+            ]
+        );
+        assert_eq!(account.available(), Amount::from_units(100_000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn withdrawal_above_balance_is_stored_as_rejected() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = ClientAccount::new_account(1);
+        let result = account
+            .execute_withdrawal(
+                &store,
+                csv_transaction(CSVTransactionType::Withdrawal, 1, Some(4)).try_into()?,
+                &FeeSchedule::default(),
+                WithdrawalLimits::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(RuntimeErrorType::BalanceIssues(
+                _
+            )))
+        ));
+        let transaction = store.get(1).await?.expect("stored as rejected");
+        assert_eq!(transaction.state(), SerializableState::Rejected);
+
+        let result = account
+            .execute_dispute(
+                &store,
+                csv_transaction(CSVTransactionType::Dispute, 1, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(
+                RuntimeErrorType::WrongTransactionState(_)
+            ))
+        ));
+        assert_eq!(account.available(), Amount::ZERO);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frozen_accounts_take_disputes_only_if_allowed() -> Result<(), RuntimeError> {
+        let store = MemoryTransactionStore::new();
+        let mut account = ClientAccount::new_account(1);
+        account
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 1, Some(10)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await?;
+        account.freeze(2, "fraud review".to_string())?;
+
+        let result = account
+            .execute_dispute(
+                &store,
+                csv_transaction(CSVTransactionType::Dispute, 1, None).try_into()?,
+                DisputeRules::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(RuntimeErrorType::FrozenAccount(
+                _
+            )))
+        ));
+
+        let rules = DisputeRules {
+            frozen_accounts: true,
+            ..DisputeRules::default()
+        };
+        dispute_then_resolve(&mut account, &store, rules).await?;
+        assert_eq!(account.available(), Amount::from_units(100_000));
+        let result = account
+            .execute_deposit(
+                &store,
+                csv_transaction(CSVTransactionType::Deposit, 3, Some(1)).try_into()?,
+                &FeeSchedule::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Recoverable(RuntimeErrorType::FrozenAccount(
+                _
+            )))
+        ));
+        Ok(())
     }
 }
@@ -0,0 +1,348 @@
+use crate::client_account::ClientAccount;
+use crate::constants::MAILBOX_SIZE;
+use crate::engine::{self, Rules};
+use crate::error::RuntimeError::{NonRecoverable, Recoverable};
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::history::{AccountEvent, Snapshot};
+use crate::ledger::JournalEntry;
+use crate::policy::ErrorPolicy;
+use crate::report::{Rejection, SourceRecord};
+use crate::store::TransactionStore;
+use crate::transaction::CSVTransaction;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// What a client actor is asked to do. Messages are handled in the order they were sent.
+pub(crate) enum ClientMessage {
+    Transaction(SourceRecord, CSVTransaction),
+    /// Answered with a copy of the account once every earlier message was handled
+    Snapshot(oneshot::Sender<ClientAccount>),
+    /// Hands the account out once every earlier message was handled and waits until it
+    /// comes back through `returned`, so another client can apply a transaction touching both
+    Lend {
+        account: oneshot::Sender<ClientAccount>,
+        returned: oneshot::Receiver<ClientAccount>,
+    },
+    /// A transaction that also touches `peer`, lent by the actor of that client. `peer`
+    /// goes back through `returned` once applied.
+    WithPeer {
+        source: SourceRecord,
+        transaction: CSVTransaction,
+        peer: ClientAccount,
+        returned: oneshot::Sender<ClientAccount>,
+    },
+}
+
+/// What client actors report back to the runner
+pub(crate) enum ClientEvent {
+    Rejected(Rejection),
+    /// Journal entries of the transactions applied since the last event
+    Posted(Vec<JournalEntry>),
+    /// What a row did to the accounts it touched, only sent when history is recorded
+    Recorded(Vec<AccountEvent>),
+    /// A transaction failed under `ErrorPolicy::Halt`, the client ignores everything after it.
+    /// Carries the rejection of the row that failed.
+    Halted(Rejection, RuntimeError),
+}
+
+/// Long lived task owning the account of one client. Transactions reach it through a
+/// bounded mailbox, so they are applied in input order and a slow client only holds up the
+/// reader once its mailbox is full.
+pub(crate) struct ClientActor {
+    client_id: u16,
+    mailbox: mpsc::Sender<ClientMessage>,
+    handle: JoinHandle<ClientAccount>,
+}
+
+impl ClientActor {
+    pub(crate) fn spawn(
+        account: ClientAccount,
+        store: Arc<dyn TransactionStore>,
+        error_policy: ErrorPolicy,
+        rules: Rules,
+        history: bool,
+        events: mpsc::UnboundedSender<ClientEvent>,
+    ) -> Self {
+        let client_id = account.id;
+        let (mailbox, receiver) = mpsc::channel(MAILBOX_SIZE);
+        let handle = tokio::spawn(Self::run(
+            account,
+            store,
+            error_policy,
+            rules,
+            history,
+            receiver,
+            events,
+        ));
+        Self {
+            client_id,
+            mailbox,
+            handle,
+        }
+    }
+
+    /// Waits for room in the mailbox
+    pub(crate) async fn send(&self, source: SourceRecord, transaction: CSVTransaction) {
+        // the receiver only goes away if the task panicked, which `stop` reports
+        let _ = self
+            .mailbox
+            .send(ClientMessage::Transaction(source, transaction))
+            .await;
+    }
+
+    /// The account after every transaction sent so far
+    pub(crate) async fn snapshot(&self) -> Result<ClientAccount, RuntimeError> {
+        let (reply, receiver) = oneshot::channel();
+        let _ = self.mailbox.send(ClientMessage::Snapshot(reply)).await;
+        receiver.await.map_err(|_| {
+            NonRecoverable(RuntimeErrorType::CheckpointError(format!(
+                "Client {} stopped before its snapshot",
+                self.client_id
+            )))
+        })
+    }
+
+    /// Borrows the account once every transaction sent so far was applied. The actor waits
+    /// until it is handed back through the returned sender.
+    pub(crate) async fn lend(
+        &self,
+    ) -> Result<(ClientAccount, oneshot::Sender<ClientAccount>), RuntimeError> {
+        let (lent, receiver) = oneshot::channel();
+        let (returned, returned_receiver) = oneshot::channel();
+        let _ = self
+            .mailbox
+            .send(ClientMessage::Lend {
+                account: lent,
+                returned: returned_receiver,
+            })
+            .await;
+        let account = receiver.await.map_err(|_| {
+            NonRecoverable(RuntimeErrorType::WrongAccountState(format!(
+                "Client {} stopped before lending its account",
+                self.client_id
+            )))
+        })?;
+        Ok((account, returned))
+    }
+
+    /// Like `send`, for a transaction that also touches `peer`, lent by another actor
+    pub(crate) async fn send_with_peer(
+        &self,
+        source: SourceRecord,
+        transaction: CSVTransaction,
+        peer: ClientAccount,
+        returned: oneshot::Sender<ClientAccount>,
+    ) {
+        let _ = self
+            .mailbox
+            .send(ClientMessage::WithPeer {
+                source,
+                transaction,
+                peer,
+                returned,
+            })
+            .await;
+    }
+
+    /// Closes the mailbox and hands the account back once everything sent was applied
+    pub(crate) async fn stop(self) -> Result<ClientAccount, RuntimeError> {
+        drop(self.mailbox);
+        self.handle
+            .await
+            .map_err(|e| NonRecoverable(RuntimeErrorType::JoinError(e)))
+    }
+
+    async fn run(
+        mut account: ClientAccount,
+        store: Arc<dyn TransactionStore>,
+        error_policy: ErrorPolicy,
+        rules: Rules,
+        history: bool,
+        mut mailbox: mpsc::Receiver<ClientMessage>,
+        events: mpsc::UnboundedSender<ClientEvent>,
+    ) -> ClientAccount {
+        let mut halted = false;
+        while let Some(message) = mailbox.recv().await {
+            let (source, tx_id, result) = match message {
+                ClientMessage::Snapshot(reply) => {
+                    let _ = reply.send(account.clone());
+                    continue;
+                }
+                ClientMessage::Lend {
+                    account: lent,
+                    returned,
+                } => {
+                    let _ = lent.send(account.clone());
+                    // a borrower that went away without handing it back changed nothing
+                    if let Ok(x) = returned.await {
+                        account = x;
+                    }
+                    continue;
+                }
+                ClientMessage::Transaction(_, _) if halted => continue,
+                ClientMessage::WithPeer { peer, returned, .. } if halted => {
+                    let _ = returned.send(peer);
+                    continue;
+                }
+                ClientMessage::Transaction(source, transaction) => {
+                    let tx_id = transaction.tx_id;
+                    let snapshot =
+                        history.then(|| Snapshot::take(source.line, &transaction, [&account]));
+                    let result =
+                        engine::execute(&mut account, store.as_ref(), transaction, &rules).await;
+                    let journal = account.take_journal();
+                    let recorded = snapshot.map(|x| x.events([&account], &journal, &result));
+                    Self::report(&events, recorded, journal);
+                    (source, tx_id, result)
+                }
+                ClientMessage::WithPeer {
+                    source,
+                    transaction,
+                    mut peer,
+                    returned,
+                } => {
+                    let tx_id = transaction.tx_id;
+                    let snapshot = history
+                        .then(|| Snapshot::take(source.line, &transaction, [&account, &peer]));
+                    let result = engine::execute_with_peer(
+                        &mut account,
+                        &mut peer,
+                        store.as_ref(),
+                        transaction,
+                        &rules,
+                    )
+                    .await;
+                    let journal = account.take_journal();
+                    let recorded = snapshot.map(|x| x.events([&account, &peer], &journal, &result));
+                    // reported before the peer's actor gets its account back and reports its own
+                    Self::report(&events, recorded, journal);
+                    let _ = returned.send(peer);
+                    (source, tx_id, result)
+                }
+            };
+            let event = match result {
+                Ok(()) => continue,
+                Err(Recoverable(e_type)) => ClientEvent::Rejected(Rejection::new(&source, &e_type)),
+                Err(NonRecoverable(e_type)) => match error_policy {
+                    ErrorPolicy::Halt => {
+                        halted = true;
+                        ClientEvent::Halted(
+                            Rejection::new(&source, &e_type),
+                            NonRecoverable(RuntimeErrorType::TransactionFailed {
+                                client_id: account.id,
+                                tx_id,
+                                error: Box::new(e_type),
+                            }),
+                        )
+                    }
+                    ErrorPolicy::Freeze => {
+                        log::error!(
+                            "Freezing client {} after tx {}: {}",
+                            account.id,
+                            tx_id,
+                            e_type
+                        );
+                        let _ = account.freeze(tx_id, e_type.to_string()); // may be frozen already
+                        ClientEvent::Rejected(Rejection::new(&source, &e_type))
+                    }
+                    ErrorPolicy::Skip => {
+                        log::error!("Skipping tx {} of client {}: {}", tx_id, account.id, e_type);
+                        ClientEvent::Rejected(Rejection::new(&source, &e_type))
+                    }
+                },
+            };
+            // the runner keeps its receiver for as long as actors run
+            let _ = events.send(event);
+        }
+        account
+    }
+
+    fn report(
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        recorded: Option<Vec<AccountEvent>>,
+        journal: Vec<JournalEntry>,
+    ) {
+        if let Some(recorded) = recorded {
+            let _ = events.send(ClientEvent::Recorded(recorded));
+        }
+        if !journal.is_empty() {
+            let _ = events.send(ClientEvent::Posted(journal));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::store::MemoryTransactionStore;
+    use crate::transaction::CSVTransactionType;
+
+    fn source(line: u64) -> SourceRecord {
+        SourceRecord {
+            line,
+            raw: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn halted_client_ignores_later_transactions() -> Result<(), RuntimeError> {
+        let (events, mut event_receiver) = mpsc::unbounded_channel();
+        let actor = ClientActor::spawn(
+            ClientAccount::new_account(1),
+            Arc::new(MemoryTransactionStore::new()),
+            ErrorPolicy::Halt,
+            Rules::default(),
+            false,
+            events,
+        );
+        let deposit = |tx_id| {
+            CSVTransaction::new(
+                CSVTransactionType::Deposit,
+                1,
+                tx_id,
+                Some(Amount::from_units(10_000)),
+            )
+        };
+        actor.send(source(2), deposit(1)).await;
+        // dispute needs more than is available once the withdrawal went through
+        actor
+            .send(
+                source(3),
+                CSVTransaction::new(
+                    CSVTransactionType::Withdrawal,
+                    1,
+                    2,
+                    Some(Amount::from_units(5_000)),
+                ),
+            )
+            .await;
+        actor
+            .send(
+                source(4),
+                CSVTransaction::new(CSVTransactionType::Dispute, 1, 1, None),
+            )
+            .await;
+        actor.send(source(5), deposit(3)).await;
+
+        let account = actor.snapshot().await?;
+        assert_eq!(account.available(), Amount::from_units(5_000));
+        // the deposit and the withdrawal were posted before the dispute failed
+        for _ in 0..2 {
+            assert!(matches!(
+                event_receiver.try_recv(),
+                Ok(ClientEvent::Posted(_))
+            ));
+        }
+        assert!(matches!(
+            event_receiver.try_recv(),
+            Ok(ClientEvent::Halted(
+                _,
+                NonRecoverable(RuntimeErrorType::TransactionFailed { tx_id: 1, .. })
+            ))
+        ));
+        assert_eq!(actor.stop().await?.available(), Amount::from_units(5_000));
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use crate::constants::{
+    BATCH_SIZE, DEFAULT_MAX_DISPUTE_CYCLES, DEFAULT_OUTPUT_SCALE, SECONDS_PER_DAY,
+    TEMP_DIRECTORY_LOC,
+};
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::fee::FeeSchedule;
+use crate::limits::WithdrawalLimits;
+use crate::output::OutputFormat;
+use crate::policy::{DisputePolicy, ErrorPolicy, ExpiredDisputePolicy, FeePolicy};
+use crate::store::StoreBackend;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Settings of a run. Every field is optional in the TOML file and falls back to the
+/// compile time default, command line flags override both.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    /// Rows read at once, and between two checkpoints
+    pub batch_size: usize,
+    /// Where runners create their scratch directories
+    pub scratch_root: PathBuf,
+    /// Where `Runner` keeps transactions for later disputes
+    pub store: StoreBackend,
+    /// Keeps the scratch directory of a run once the runner is dropped
+    pub retain_scratch: bool,
+    /// Threads of the runtime, one per core if not set
+    pub workers: Option<usize>,
+    pub output_format: OutputFormat,
+    pub output_scale: usize,
+    pub error_policy: ErrorPolicy,
+    pub dispute_policy: DisputePolicy,
+    /// How often one transaction can be disputed, resolved disputes included
+    pub max_dispute_cycles: u32,
+    /// Whether disputes, resolves and chargebacks still go through on frozen accounts
+    pub frozen_account_disputes: bool,
+    /// What a chargeback does with the fee of the transaction
+    pub fee_policy: FeePolicy,
+    /// Days after a transaction a dispute is still taken, needs the timestamp column
+    pub dispute_window_days: Option<u32>,
+    /// Days a dispute can stay open before `expired_disputes` settles it
+    pub dispute_deadline_days: Option<u32>,
+    pub expired_disputes: ExpiredDisputePolicy,
+    pub log_level: LevelFilter,
+    /// Only settable in the config file, as a `[fees]` table
+    pub fees: FeeSchedule,
+    /// Only settable in the config file, as a `[limits]` table
+    pub limits: WithdrawalLimits,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: BATCH_SIZE,
+            scratch_root: PathBuf::from(TEMP_DIRECTORY_LOC),
+            store: StoreBackend::default(),
+            retain_scratch: false,
+            workers: None,
+            output_format: OutputFormat::default(),
+            output_scale: DEFAULT_OUTPUT_SCALE,
+            error_policy: ErrorPolicy::default(),
+            dispute_policy: DisputePolicy::default(),
+            max_dispute_cycles: DEFAULT_MAX_DISPUTE_CYCLES,
+            frozen_account_disputes: false,
+            fee_policy: FeePolicy::default(),
+            dispute_window_days: None,
+            dispute_deadline_days: None,
+            expired_disputes: ExpiredDisputePolicy::default(),
+            log_level: LevelFilter::Warn,
+            fees: FeeSchedule::default(),
+            limits: WithdrawalLimits::default(),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Reads a TOML file like
+    /// ```toml
+    /// batch_size = 5000
+    /// error_policy = "freeze"
+    /// ```
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RuntimeError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| config_error(format!("Couldn't read {}: {}", path.display(), e)))?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, RuntimeError> {
+        let config: Self = toml::from_str(contents).map_err(|e| config_error(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), RuntimeError> {
+        if self.batch_size == 0 {
+            return Err(config_error("batch_size must be at least 1".to_string()));
+        }
+        if self.workers == Some(0) {
+            return Err(config_error("workers must be at least 1".to_string()));
+        }
+        if self.dispute_window_days == Some(0) || self.dispute_deadline_days == Some(0) {
+            return Err(config_error(
+                "dispute_window_days and dispute_deadline_days must be at least 1".to_string(),
+            ));
+        }
+        self.fees.validate()?;
+        self.limits.validate()
+    }
+
+    /// `dispute_window_days` in seconds
+    pub fn dispute_window(&self) -> Option<u64> {
+        self.dispute_window_days
+            .map(|x| u64::from(x) * SECONDS_PER_DAY)
+    }
+
+    /// `dispute_deadline_days` in seconds
+    pub fn dispute_deadline(&self) -> Option<u64> {
+        self.dispute_deadline_days
+            .map(|x| u64::from(x) * SECONDS_PER_DAY)
+    }
+}
+
+fn config_error(message: String) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ConfigError(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_fall_back_to_defaults() -> Result<(), RuntimeError> {
+        let config = EngineConfig::parse(
+            r#"
+batch_size = 500
+output_format = "jsonl"
+store = "log"
+error_policy = "skip"
+dispute_policy = "withdrawal-reversal"
+log_level = "debug"
+"#,
+        )?;
+        assert_eq!(config.batch_size, 500);
+        assert_eq!(config.output_format, OutputFormat::JsonLines);
+        assert_eq!(config.store, StoreBackend::Log);
+        assert_eq!(config.error_policy, ErrorPolicy::Skip);
+        assert_eq!(config.dispute_policy, DisputePolicy::WithdrawalReversal);
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.scratch_root, PathBuf::from(TEMP_DIRECTORY_LOC));
+        assert_eq!(config.workers, None);
+
+        assert!(EngineConfig::parse("batch_size = 0").is_err());
+        assert!(EngineConfig::parse("batch = 10").is_err());
+
+        let config = EngineConfig::parse(
+            r#"
+fee_policy = "refund"
+[fees]
+withdrawal = [{ up_to = "100", flat = "1" }, { percent = "0.5" }]
+"#,
+        )?;
+        assert_eq!(config.fee_policy, FeePolicy::Refund);
+        assert_eq!(config.fees.withdrawal.len(), 2);
+        assert_eq!(config.fees.withdrawal[1].up_to, None);
+        assert!(EngineConfig::parse("[fees]\ndeposit = [{ percent = \"101\" }]").is_err());
+
+        let config = EngineConfig::parse(
+            r#"
+dispute_window_days = 30
+dispute_deadline_days = 7
+expired_disputes = "chargeback"
+"#,
+        )?;
+        assert_eq!(config.dispute_window(), Some(30 * SECONDS_PER_DAY));
+        assert_eq!(config.dispute_deadline(), Some(7 * SECONDS_PER_DAY));
+        assert_eq!(config.expired_disputes, ExpiredDisputePolicy::Chargeback);
+        assert!(EngineConfig::parse("dispute_deadline_days = 0").is_err());
+        Ok(())
+    }
+}
@@ -1,2 +1,13 @@
+use crate::amount::AMOUNT_SCALE;
+
 pub(crate) const BATCH_SIZE: usize = 10_000;
+// transactions queued per client before the reader waits for it
+pub(crate) const MAILBOX_SIZE: usize = 1_024;
 pub(crate) const TEMP_DIRECTORY_LOC: &str = "tmp/";
+pub(crate) const DEFAULT_OUTPUT_SCALE: usize = AMOUNT_SCALE;
+// a resolved transaction can not be disputed again
+pub(crate) const DEFAULT_MAX_DISPUTE_CYCLES: u32 = 1;
+// timestamps are unix seconds, dispute windows are set in days
+pub(crate) const SECONDS_PER_DAY: u64 = 86_400;
+// currency of rows that don't name one
+pub(crate) const DEFAULT_CURRENCY: [u8; 3] = *b"USD";
@@ -0,0 +1,90 @@
+use crate::constants::DEFAULT_CURRENCY;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Three letter ISO 4217 style code, kept upper case. Rows without a currency are in
+/// the default one, `USD`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// The code as three ascii bytes, for fixed size records
+    pub(crate) fn bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 3]) -> Result<Self, RuntimeError> {
+        match std::str::from_utf8(&bytes) {
+            Ok(x) => Currency::from_str(x),
+            Err(_) => Err(parse_error(&format!("{:?}", bytes))),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("Checked to be ascii letters when parsed")
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency(DEFAULT_CURRENCY)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Currency {
+    type Err = RuntimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 3] = s.as_bytes().try_into().map_err(|_| parse_error(s))?;
+        if !bytes.iter().all(u8::is_ascii_alphabetic) {
+            return Err(parse_error(s));
+        }
+        Ok(Currency(bytes.map(|x| x.to_ascii_uppercase())))
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = RuntimeError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Currency::from_str(&value)
+    }
+}
+
+impl From<Currency> for String {
+    fn from(value: Currency) -> Self {
+        value.to_string()
+    }
+}
+
+fn parse_error(s: &str) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(format!(
+        "Currency {:?} is not a three letter code",
+        s
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_three_letter_codes() {
+        assert_eq!(Currency::from_str("eur").unwrap().to_string(), "EUR");
+        assert_eq!(Currency::default().to_string(), "USD");
+        assert!(Currency::from_str("EURO").is_err());
+        assert!(Currency::from_str("U$D").is_err());
+        let x = Currency::from_str("GBP").unwrap();
+        assert_eq!(Currency::from_bytes(x.bytes()).unwrap(), x);
+    }
+}
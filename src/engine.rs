@@ -0,0 +1,528 @@
+use crate::client_account::ClientAccount;
+use crate::config::EngineConfig;
+use crate::error::RuntimeError::Recoverable;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::fee::FeeSchedule;
+use crate::history::{History, Snapshot};
+use crate::ledger::{JournalEntry, Ledger};
+use crate::limits::WithdrawalLimits;
+use crate::output::{self, OutputFormat};
+use crate::policy::{DisputePolicy, DisputeRules, ExpiredDisputePolicy, FeePolicy};
+use crate::store::{
+    MemoryTransactionStore, SerializableTransaction, SerializableTransactionType, TransactionStore,
+};
+use crate::transaction::{CSVTransaction, CSVTransactionType, TransferRequest};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Applies transactions one at a time as they are handed in, for services that embed the
+/// processor instead of feeding it a csv through `Runner`. Transactions are kept in memory.
+pub struct Engine {
+    accounts: HashMap<u16, ClientAccount>,
+    store: Arc<dyn TransactionStore>,
+    rules: Rules,
+    clock: Option<u64>, // latest timestamp submitted
+    ledger: Ledger,
+    journal: Vec<JournalEntry>, // posted since the last `take_journal`
+    history: Option<History>,   // only kept if asked for
+    submitted: u64,             // stands in for the line of events
+}
+
+/// Everything applying a transaction depends on besides the accounts and the store
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Rules {
+    pub(crate) disputes: DisputeRules,
+    pub(crate) fees: Arc<FeeSchedule>,
+    pub(crate) limits: WithdrawalLimits,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            store: Arc::new(MemoryTransactionStore::new()),
+            rules: Rules::default(),
+            clock: None,
+            ledger: Ledger::default(),
+            journal: vec![],
+            history: None,
+            submitted: 0,
+        }
+    }
+
+    /// Takes the dispute settings, the fees and the withdrawal limits of `config`
+    pub fn with_config(self, config: &EngineConfig) -> Self {
+        let mut engine = self
+            .with_dispute_policy(config.dispute_policy)
+            .with_max_dispute_cycles(config.max_dispute_cycles)
+            .with_frozen_account_disputes(config.frozen_account_disputes)
+            .with_fees(config.fees.clone())
+            .with_fee_policy(config.fee_policy)
+            .with_withdrawal_limits(config.limits);
+        engine.rules.disputes.window = config.dispute_window();
+        engine.rules.disputes.deadline = config.dispute_deadline();
+        engine.rules.disputes.expired = config.expired_disputes;
+        engine
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.rules.disputes.policy = dispute_policy;
+        self
+    }
+
+    pub fn with_max_dispute_cycles(mut self, max_cycles: u32) -> Self {
+        self.rules.disputes.max_cycles = max_cycles;
+        self
+    }
+
+    /// Lets disputes, resolves and chargebacks through on frozen accounts
+    pub fn with_frozen_account_disputes(mut self, allowed: bool) -> Self {
+        self.rules.disputes.frozen_accounts = allowed;
+        self
+    }
+
+    /// Fees charged on deposits and withdrawals, none by default
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.rules.fees = Arc::new(fees);
+        self
+    }
+
+    pub fn with_fee_policy(mut self, fee_policy: FeePolicy) -> Self {
+        self.rules.disputes.fee_policy = fee_policy;
+        self
+    }
+
+    /// Rules withdrawals are checked against, none by default
+    pub fn with_withdrawal_limits(mut self, limits: WithdrawalLimits) -> Self {
+        self.rules.limits = limits;
+        self
+    }
+
+    /// Seconds after a transaction it can still be disputed, if both have a timestamp
+    pub fn with_dispute_window(mut self, window: u64) -> Self {
+        self.rules.disputes.window = Some(window);
+        self
+    }
+
+    /// Seconds a dispute can stay open before `expired` settles it
+    pub fn with_dispute_deadline(mut self, deadline: u64, expired: ExpiredDisputePolicy) -> Self {
+        self.rules.disputes.deadline = Some(deadline);
+        self.rules.disputes.expired = expired;
+        self
+    }
+
+    /// Records an `AccountEvent` for every transaction, numbered in the order they were
+    /// submitted, so `history` can tell the balance of a client at any point
+    pub fn with_history(mut self) -> Self {
+        self.history = Some(History::default());
+        self
+    }
+
+    /// Applies `transaction` to the account of its client, opening the account if needed.
+    /// A `Recoverable` error means only this transaction was rejected.
+    /// A timestamp moves the clock on, disputes it leaves past their deadline are settled first.
+    pub async fn submit(&mut self, transaction: CSVTransaction) -> Result<(), RuntimeError> {
+        if let Some(timestamp) = transaction.timestamp {
+            if self.clock.is_none_or(|x| x < timestamp) {
+                self.clock = Some(timestamp);
+                self.settle_expired_disputes(timestamp).await?;
+            }
+        }
+        self.apply(transaction).await
+    }
+
+    /// Settles every dispute that was open longer than the deadline at `now`. Failures
+    /// are logged, the dispute stays open.
+    async fn settle_expired_disputes(&mut self, now: u64) -> Result<(), RuntimeError> {
+        let deadline = match self.rules.disputes.deadline {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+        let expired = self
+            .store
+            .expired_disputes(now.saturating_sub(deadline))
+            .await?;
+        for transaction in expired {
+            let settlement = settlement(&transaction, self.rules.disputes.expired, now);
+            if let Err(e) = self.apply(settlement).await {
+                log::error!(
+                    "Couldn't settle expired dispute of tx {}: {}",
+                    transaction.tx_id,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply(&mut self, transaction: CSVTransaction) -> Result<(), RuntimeError> {
+        let client_id = transaction.client_id;
+        let peer_id = match transaction.transaction_type {
+            CSVTransactionType::Transfer => transaction.destination,
+            CSVTransactionType::Chargeback => self
+                .store
+                .get(transaction.tx_id)
+                .await?
+                .filter(|s| s.transaction_type == SerializableTransactionType::Transfer)
+                .map(|s| s.client_id),
+            _ => None,
+        };
+        let mut peer = match peer_id {
+            Some(x) if x != client_id => Some(match self.accounts.remove(&x) {
+                Some(account) => (account, true),
+                None => (ClientAccount::new_account(x), false),
+            }),
+            _ => None,
+        };
+        let account = self
+            .accounts
+            .entry(client_id)
+            .or_insert_with(|| ClientAccount::new_account(client_id));
+        let store = self.store.as_ref();
+        self.submitted += 1;
+        let line = self.submitted;
+        let snapshot = self.history.is_some().then(|| {
+            let touched = std::iter::once(&*account).chain(peer.as_ref().map(|x| &x.0));
+            Snapshot::take(line, &transaction, touched)
+        });
+        let result = match &mut peer {
+            Some((peer, _)) => {
+                execute_with_peer(account, peer, store, transaction, &self.rules).await
+            }
+            None => execute(account, store, transaction, &self.rules).await,
+        };
+        let journal = account.take_journal();
+        let recorded = snapshot.map(|x| {
+            let touched = std::iter::once(&*account).chain(peer.as_ref().map(|x| &x.0));
+            x.events(touched, &journal, &result)
+        });
+        if let (Some(history), Some(recorded)) = (&mut self.history, recorded) {
+            history.extend(recorded);
+        }
+        // a new peer only gets an account once a transaction went through
+        if let Some((peer, existed)) = peer {
+            if existed || result.is_ok() {
+                self.accounts.insert(peer.id, peer);
+            }
+        }
+        for entry in &journal {
+            self.ledger.post(entry)?;
+        }
+        self.journal.extend(journal);
+        result
+    }
+
+    /// Balance of every ledger account, `Ledger::verify` checks it against `accounts`
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Every transaction applied to each client, `None` unless `with_history` asked for it
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Journal entries of every transaction applied since the last call
+    pub fn take_journal(&mut self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.journal)
+    }
+
+    pub fn account(&self, client_id: u16) -> Option<&ClientAccount> {
+        self.accounts.get(&client_id)
+    }
+
+    /// Every account seen so far, in no particular order
+    pub fn accounts(&self) -> impl Iterator<Item = &ClientAccount> {
+        self.accounts.values()
+    }
+
+    /// Writes every account to `writer`, sorted by client id, amounts with `scale` decimals
+    pub fn write_accounts(
+        &self,
+        writer: impl Write,
+        format: OutputFormat,
+        scale: usize,
+    ) -> Result<(), RuntimeError> {
+        let mut accounts: Vec<ClientAccount> = self.accounts.values().cloned().collect();
+        output::write_accounts(&mut accounts, writer, format, scale)
+    }
+}
+
+/// The row settling the expired dispute of `transaction` under `expired`, made by the
+/// client that disputed it
+pub(crate) fn settlement(
+    transaction: &SerializableTransaction,
+    expired: ExpiredDisputePolicy,
+    now: u64,
+) -> CSVTransaction {
+    let transaction_type = match expired {
+        ExpiredDisputePolicy::Resolve => CSVTransactionType::Resolve,
+        ExpiredDisputePolicy::Chargeback => CSVTransactionType::Chargeback,
+    };
+    let client_id = match transaction.transaction_type {
+        SerializableTransactionType::Transfer => {
+            transaction.destination.unwrap_or(transaction.client_id)
+        }
+        _ => transaction.client_id,
+    };
+    CSVTransaction::new(transaction_type, client_id, transaction.tx_id, None).with_timestamp(now)
+}
+
+/// Applies one transaction to the account of its client
+pub(crate) async fn execute(
+    account: &mut ClientAccount,
+    store: &dyn TransactionStore,
+    transaction: CSVTransaction,
+    rules: &Rules,
+) -> Result<(), RuntimeError> {
+    account.velocity.next_row();
+    apply(account, store, transaction, rules).await
+}
+
+/// Applies a transaction that touches a second account. `peer` is the destination of a
+/// transfer, or for the chargeback of a transfer the client it came from.
+pub(crate) async fn execute_with_peer(
+    account: &mut ClientAccount,
+    peer: &mut ClientAccount,
+    store: &dyn TransactionStore,
+    transaction: CSVTransaction,
+    rules: &Rules,
+) -> Result<(), RuntimeError> {
+    account.velocity.next_row();
+    match transaction.transaction_type {
+        CSVTransactionType::Transfer => {
+            account
+                .execute_transfer(peer, store, request(transaction)?)
+                .await
+        }
+        CSVTransactionType::Chargeback => {
+            account
+                .execute_transfer_chargeback(peer, store, request(transaction)?, rules.disputes)
+                .await
+        }
+        _ => apply(account, store, transaction, rules).await,
+    }
+}
+
+async fn apply(
+    account: &mut ClientAccount,
+    store: &dyn TransactionStore,
+    transaction: CSVTransaction,
+    rules: &Rules,
+) -> Result<(), RuntimeError> {
+    match transaction.transaction_type {
+        CSVTransactionType::Deposit => {
+            //takes a state 1 transaction and writes it
+            account
+                .execute_deposit(store, request(transaction)?, &rules.fees)
+                .await
+        }
+        CSVTransactionType::Withdrawal => {
+            //takes a state 1 transaction and writes it
+            account
+                .execute_withdrawal(store, request(transaction)?, &rules.fees, rules.limits)
+                .await
+        }
+        CSVTransactionType::Dispute => {
+            //Finds a state1 transaction in the store
+            // Converts it into state 2
+            account
+                .execute_dispute(store, request(transaction)?, rules.disputes)
+                .await
+        }
+        CSVTransactionType::Resolve => {
+            //Finds a state2 transaction in the store
+            //Writes it back to state 3
+            account
+                .execute_resolve(store, request(transaction)?, rules.disputes)
+                .await
+        }
+        CSVTransactionType::Chargeback => {
+            //Finds a state2 transaction in the store
+            //Writes it back to state 3
+            account
+                .execute_chargeback(store, request(transaction)?, rules.disputes)
+                .await
+        }
+        CSVTransactionType::Transfer => {
+            let _: TransferRequest = request(transaction)?;
+            Err(Recoverable(RuntimeErrorType::ParseError(
+                "Transfer needs the destination account".to_string(),
+            )))
+        }
+        CSVTransactionType::Unlock => account.execute_unlock(request(transaction)?),
+        CSVTransactionType::Freeze => account.execute_freeze(request(transaction)?),
+        CSVTransactionType::Unfreeze => account.execute_unfreeze(request(transaction)?),
+    }
+}
+
+/// A malformed row is rejected on its own instead of stopping the run
+fn request<T>(transaction: CSVTransaction) -> Result<T, RuntimeError>
+where
+    T: TryFrom<CSVTransaction, Error = RuntimeError>,
+{
+    T::try_from(transaction).map_err(|e| Recoverable(e.into_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn submitted_transactions_update_accounts() -> Result<(), RuntimeError> {
+        let mut engine = Engine::new();
+        let amount = Amount::from_str("2.5")?;
+        engine
+            .submit(CSVTransaction::new(
+                CSVTransactionType::Deposit,
+                1,
+                1,
+                Some(amount),
+            ))
+            .await?;
+        let result = engine
+            .submit(CSVTransaction::new(
+                CSVTransactionType::Withdrawal,
+                1,
+                2,
+                Some(Amount::from_str("3")?),
+            ))
+            .await;
+        assert!(matches!(
+            result,
+            Err(Recoverable(RuntimeErrorType::BalanceIssues(_)))
+        ));
+
+        let account = engine.account(1).expect("opened by the deposit");
+        assert_eq!(account.available(), amount);
+        assert_eq!(account.total()?, amount);
+        assert!(engine.account(2).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transfers_update_both_accounts() -> Result<(), RuntimeError> {
+        let mut engine = Engine::new();
+        engine
+            .submit(CSVTransaction::new(
+                CSVTransactionType::Deposit,
+                1,
+                1,
+                Some(Amount::from_str("5")?),
+            ))
+            .await?;
+        engine
+            .submit(
+                CSVTransaction::new(
+                    CSVTransactionType::Transfer,
+                    1,
+                    2,
+                    Some(Amount::from_str("2")?),
+                )
+                .with_destination(2),
+            )
+            .await?;
+        let result = engine
+            .submit(
+                CSVTransaction::new(
+                    CSVTransactionType::Transfer,
+                    1,
+                    3,
+                    Some(Amount::from_str("1")?),
+                )
+                .with_destination(1),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Recoverable(RuntimeErrorType::ParseError(_)))
+        ));
+        for transaction_type in [CSVTransactionType::Dispute, CSVTransactionType::Chargeback] {
+            engine
+                .submit(CSVTransaction::new(transaction_type, 2, 2, None))
+                .await?;
+        }
+
+        let result = engine
+            .submit(
+                CSVTransaction::new(
+                    CSVTransactionType::Transfer,
+                    1,
+                    4,
+                    Some(Amount::from_str("100")?),
+                )
+                .with_destination(3),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(Recoverable(RuntimeErrorType::BalanceIssues(_)))
+        ));
+        assert!(engine.account(3).is_none());
+
+        let source = engine.account(1).expect("opened by the deposit");
+        let destination = engine.account(2).expect("opened by the transfer");
+        assert_eq!(source.available(), Amount::from_str("5")?);
+        assert_eq!(destination.total()?, Amount::ZERO);
+        assert!(destination.locked());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_disputes_are_rejected_and_expired_ones_resolved() -> Result<(), RuntimeError> {
+        const DAY: u64 = 86_400;
+        let mut engine = Engine::new()
+            .with_dispute_window(30 * DAY)
+            .with_dispute_deadline(7 * DAY, ExpiredDisputePolicy::Resolve);
+        let at = |transaction: CSVTransaction, day: u64| transaction.with_timestamp(day * DAY);
+        for tx_id in [1, 2] {
+            let deposit = CSVTransaction::new(
+                CSVTransactionType::Deposit,
+                1,
+                tx_id,
+                Some(Amount::from_str("5")?),
+            );
+            engine.submit(at(deposit, 0)).await?;
+        }
+        let late = engine
+            .submit(at(
+                CSVTransaction::new(CSVTransactionType::Dispute, 1, 1, None),
+                31,
+            ))
+            .await;
+        assert!(matches!(
+            late,
+            Err(Recoverable(RuntimeErrorType::DisputeRejected(_)))
+        ));
+        engine
+            .submit(at(
+                CSVTransaction::new(CSVTransactionType::Dispute, 1, 2, None),
+                30,
+            ))
+            .await?;
+        assert_eq!(engine.account(1).unwrap().held(), Amount::from_str("5")?);
+
+        // a row of any client moves the clock past the deadline
+        let deposit = CSVTransaction::new(
+            CSVTransactionType::Deposit,
+            2,
+            3,
+            Some(Amount::from_str("1")?),
+        );
+        engine.submit(at(deposit, 38)).await?;
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), Amount::from_str("10")?);
+        assert_eq!(account.held(), Amount::ZERO);
+        Ok(())
+    }
+}
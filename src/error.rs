@@ -12,7 +12,15 @@ pub enum RuntimeError {
     NonRecoverable(RuntimeErrorType),
 }
 
-#[derive(Error, Debug)]
+impl RuntimeError {
+    pub fn into_type(self) -> RuntimeErrorType {
+        match self {
+            RuntimeError::Recoverable(x) | RuntimeError::NonRecoverable(x) => x,
+        }
+    }
+}
+
+#[derive(Error, Debug, strum::IntoStaticStr)]
 pub enum RuntimeErrorType {
     #[error("Parse Error {0}")]
     ParseError(String),
@@ -22,14 +30,40 @@ pub enum RuntimeErrorType {
     CSVLineParseError(String),
     #[error("Transaction file ops {0}")]
     TransactionFileOps(String),
+    #[error("AmountOverflow {0}")]
+    AmountOverflow(String),
     #[error("BalanceIssues {0}")]
     BalanceIssues(String),
     #[error("Locked Account {0}")]
     LockedAccount(String),
+    #[error("Frozen Account {0}")]
+    FrozenAccount(String),
+    #[error("LimitExceeded {0}")]
+    LimitExceeded(String),
+    #[error("DisputeRejected {0}")]
+    DisputeRejected(String),
     #[error("WrongTransactionState {0}")]
     WrongTransactionState(String),
+    #[error("WrongAccountState {0}")]
+    WrongAccountState(String),
     #[error(transparent)]
     JoinError(#[from] JoinError),
+    #[error("Client {client_id} transaction {tx_id} failed: {error}")]
+    TransactionFailed {
+        client_id: u16,
+        tx_id: u32,
+        error: Box<RuntimeErrorType>,
+    },
+    #[error("Checkpoint {0}")]
+    CheckpointError(String),
+    #[error("LedgerImbalance {0}")]
+    LedgerImbalance(String),
+    #[error("Server {0}")]
+    ServerError(String),
+    #[error("Config {0}")]
+    ConfigError(String),
+    #[error("Halted {0}")]
+    Halted(String),
     #[error("TransactionAlreadyPresent")]
     TransactionAlreadyPresent,
     #[error(transparent)]
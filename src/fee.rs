@@ -0,0 +1,155 @@
+use crate::amount::Amount;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::{Deserialize, Serialize};
+
+/// Fees charged on deposits and withdrawals, in the currency of the transaction. Each
+/// type has its own tiers, the first tier whose `up_to` covers the amount applies and the
+/// last one covers every amount. No tiers means no fee.
+/// ```toml
+/// [fees]
+/// deposit = [{ flat = "0.25" }]
+/// withdrawal = [{ up_to = "100", flat = "1" }, { percent = "1.5" }]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub deposit: Vec<FeeTier>,
+    pub withdrawal: Vec<FeeTier>,
+}
+
+/// `flat + percent% of the amount`, truncated to four decimal places
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeTier {
+    /// Largest amount the tier applies to, `None` for every amount
+    #[serde(with = "crate::amount::decimal::optional")]
+    pub up_to: Option<Amount>,
+    #[serde(with = "crate::amount::decimal")]
+    pub flat: Amount,
+    #[serde(with = "crate::amount::decimal")]
+    pub percent: Amount,
+}
+
+impl FeeSchedule {
+    pub fn deposit_fee(&self, amount: Amount) -> Result<Amount, RuntimeError> {
+        fee(&self.deposit, amount)
+    }
+
+    pub fn withdrawal_fee(&self, amount: Amount) -> Result<Amount, RuntimeError> {
+        fee(&self.withdrawal, amount)
+    }
+
+    /// Fees can't be negative, percentages go up to 100 and tiers are sorted by `up_to`
+    /// with the last one, and only that one, open ended
+    pub fn validate(&self) -> Result<(), RuntimeError> {
+        for (name, tiers) in [("deposit", &self.deposit), ("withdrawal", &self.withdrawal)] {
+            for (i, tier) in tiers.iter().enumerate() {
+                if tier.flat.is_negative() || tier.percent.is_negative() {
+                    return Err(fee_error(format!("{} fees can't be negative", name)));
+                }
+                if tier.percent > Amount::from_units(100 * UNITS_PER_PERCENT) {
+                    return Err(fee_error(format!("{} fee is above 100 percent", name)));
+                }
+                let next = tiers.get(i + 1);
+                match (tier.up_to, next.and_then(|x| x.up_to)) {
+                    (None, _) if next.is_some() => {
+                        return Err(fee_error(format!(
+                            "Only the last {} tier can leave out up_to",
+                            name
+                        )))
+                    }
+                    (Some(x), Some(y)) if x >= y => {
+                        return Err(fee_error(format!("{} tiers must be sorted by up_to", name)))
+                    }
+                    _ => {}
+                }
+            }
+            if tiers.last().is_some_and(|x| x.up_to.is_some()) {
+                return Err(fee_error(format!(
+                    "The last {} tier can't have up_to",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+const UNITS_PER_PERCENT: i64 = 10_000;
+
+fn fee(tiers: &[FeeTier], amount: Amount) -> Result<Amount, RuntimeError> {
+    let tier = match tiers
+        .iter()
+        .find(|x| x.up_to.is_none_or(|up_to| amount <= up_to))
+    {
+        Some(x) => x,
+        None => return Ok(Amount::ZERO), // no tiers
+    };
+    let share = i128::from(amount.units()) * i128::from(tier.percent.units())
+        / i128::from(100 * UNITS_PER_PERCENT);
+    // below `amount` since the percentage is at most 100
+    tier.flat.try_add(Amount::from_units(share as i64))
+}
+
+fn fee_error(message: String) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ConfigError(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn amount(x: &str) -> Amount {
+        Amount::from_str(x).expect("valid amount")
+    }
+
+    #[test]
+    fn first_matching_tier_applies() {
+        let schedule = FeeSchedule {
+            deposit: vec![],
+            withdrawal: vec![
+                FeeTier {
+                    up_to: Some(amount("100")),
+                    flat: amount("1"),
+                    percent: Amount::ZERO,
+                },
+                FeeTier {
+                    up_to: None,
+                    flat: amount("0.5"),
+                    percent: amount("1.5"),
+                },
+            ],
+        };
+        assert!(schedule.validate().is_ok());
+        assert_eq!(schedule.deposit_fee(amount("50")).unwrap(), Amount::ZERO);
+        assert_eq!(schedule.withdrawal_fee(amount("100")).unwrap(), amount("1"));
+        // 0.5 + 1.5% of 200.0003, truncated
+        assert_eq!(
+            schedule.withdrawal_fee(amount("200.0003")).unwrap(),
+            amount("3.5")
+        );
+
+        let mut unsorted = schedule.clone();
+        unsorted.withdrawal.reverse();
+        assert!(unsorted.validate().is_err());
+    }
+
+    #[test]
+    fn bounded_last_tier_is_rejected() {
+        let schedule = FeeSchedule {
+            deposit: vec![FeeTier {
+                up_to: Some(amount("100")),
+                flat: amount("1"),
+                percent: Amount::ZERO,
+            }],
+            withdrawal: vec![],
+        };
+        assert!(matches!(
+            schedule.validate(),
+            Err(RuntimeError::NonRecoverable(RuntimeErrorType::ConfigError(
+                _
+            )))
+        ));
+    }
+}
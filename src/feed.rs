@@ -0,0 +1,175 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::input::Input;
+use crate::report::SourceRecord;
+use crate::runner::Runner;
+use crate::transaction::CSVTransaction;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+/// Header put in front of every line, so it reads like a row of a csv input
+const FEED_HEADER: &str = "type,client,tx,amount,reason,currency,timestamp\n";
+
+/// Line of the row in the input made of it, right after the header
+const ROW_LINE: u64 = 2;
+
+/// Longest row taken, in bytes. A producer sending a longer one is told so and cut off.
+const MAX_LINE_LENGTH: u64 = 4096;
+
+/// Takes csv rows over plain TCP, one per line without a header, and answers every row
+/// with a line of its own, in order:
+///
+/// - `ACK <tx>` once it was applied
+/// - `NACK <tx> <error>` with the error variant if it was rejected, `-` for a row
+///   without a tx
+///
+/// A row longer than `MAX_LINE_LENGTH` is answered `NACK - CSVLineParseError` and the
+/// connection closed.
+///
+/// Every connection is a producer. Rows are applied one at a time, so producers
+/// interleave row by row and the rows of one connection keep their order.
+pub struct FeedServer {
+    runner: Arc<Mutex<Runner>>,
+}
+
+impl FeedServer {
+    pub fn new(runner: Runner) -> Self {
+        Self::shared(Arc::new(Mutex::new(runner)))
+    }
+
+    /// Feeds a runner other front ends also go through, like `HttpServer::runner`
+    pub fn shared(runner: Arc<Mutex<Runner>>) -> Self {
+        Self { runner }
+    }
+
+    /// Runner the rows go through, to look at accounts while producers are connected
+    pub fn runner(&self) -> Arc<Mutex<Runner>> {
+        self.runner.clone()
+    }
+
+    /// Accepts producers on `listener` until `shutdown` completes. Connections already
+    /// open are served until they close.
+    pub async fn serve(
+        self,
+        listener: std::net::TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), RuntimeError> {
+        listener.set_nonblocking(true).map_err(feed_error)?;
+        let listener = TcpListener::from_std(listener).map_err(feed_error)?;
+        tokio::pin!(shutdown);
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => accepted.map_err(feed_error)?.0,
+                _ = &mut shutdown => return Ok(()),
+            };
+            let runner = self.runner.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::produce(runner, stream).await {
+                    log::error!("Feed connection failed {}", e);
+                }
+            });
+        }
+    }
+
+    async fn produce(runner: Arc<Mutex<Runner>>, stream: TcpStream) -> Result<(), RuntimeError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(MAX_LINE_LENGTH + 1)
+                .read_line(&mut line)
+                .await
+                .map_err(feed_error)?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') && read as u64 > MAX_LINE_LENGTH {
+                // the rest of it can't be told apart from the next row, so the producer goes
+                let error = RuntimeErrorType::CSVLineParseError(format!(
+                    "line longer than {} bytes",
+                    MAX_LINE_LENGTH
+                ));
+                let variant: &'static str = (&error).into();
+                writer
+                    .write_all(format!("NACK - {}\n", variant).as_bytes())
+                    .await
+                    .map_err(feed_error)?;
+                let _ = writer.shutdown().await;
+                return Err(feed_error(error));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply = Self::apply(&runner, line).await;
+            writer
+                .write_all(format!("{}\n", reply).as_bytes())
+                .await
+                .map_err(feed_error)?;
+        }
+        writer.shutdown().await.map_err(feed_error)
+    }
+
+    /// Applies one row and tells how it went. Disputes the row's timestamp settles are
+    /// not the row's doing, their rejections are only logged and reported.
+    async fn apply(runner: &Arc<Mutex<Runner>>, line: &str) -> String {
+        let csv = format!("{}{}\n", FEED_HEADER, line);
+        // read the way the runner reads it, so the tx is the one it sees
+        let record = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(csv.as_bytes())
+            .into_records()
+            .next()
+            .and_then(Result::ok);
+        let tx = match &record {
+            Some(record) => match CSVTransaction::try_from(record.clone()) {
+                Ok(transaction) => Some(transaction.tx_id),
+                // a row that doesn't parse still names its tx most of the time
+                Err(_) => record.get(2).and_then(|x| x.trim().parse().ok()),
+            },
+            None => None,
+        };
+        let tx = tx.map_or_else(|| "-".to_string(), |x: u32| x.to_string());
+        let raw = record.map(|x| SourceRecord::new(ROW_LINE, &x).raw);
+
+        let input = Input::from_reader(Cursor::new(csv));
+        let result = runner.lock().await.append(input).await;
+        match result {
+            Ok(rejected) => {
+                let (own, settlements): (Vec<_>, Vec<_>) = rejected.into_iter().partition(|x| {
+                    x.line == ROW_LINE && raw.as_ref().is_none_or(|raw| &x.record == raw)
+                });
+                for settlement in settlements {
+                    log::warn!(
+                        "Settling an expired dispute failed {} {}",
+                        settlement.record,
+                        settlement.message
+                    );
+                }
+                match own.first() {
+                    None => format!("ACK {}", tx),
+                    Some(rejection) => format!("NACK {} {}", tx, rejection.error),
+                }
+            }
+            Err(e) => {
+                // a row halting its client under the halt policy
+                let error = match e.into_type() {
+                    RuntimeErrorType::TransactionFailed { error, .. } => *error,
+                    x => x,
+                };
+                let variant: &'static str = (&error).into();
+                format!("NACK {} {}", tx, variant)
+            }
+        }
+    }
+}
+
+fn feed_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ServerError(e.to_string()))
+}
@@ -0,0 +1,252 @@
+use crate::client_account::{Balance, ClientAccount};
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::ledger::JournalEntry;
+use crate::transaction::{CSVTransaction, CSVTransactionType};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// What one row did to the balance of one client. A row touching two clients, like a
+/// transfer, leaves an event on each. Rejected rows are kept too, with the balance unchanged.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AccountEvent {
+    /// Input line of the row. Settlements of expired disputes carry the line of the row
+    /// that moved the clock, `Engine` counts submitted transactions instead.
+    pub line: u64,
+    pub tx_id: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: CSVTransactionType,
+    pub client_id: u16,
+    /// Currency the row moved money in, or its own if it moved nothing
+    pub currency: Currency,
+    pub before: Balance,
+    pub after: Balance,
+    /// Error variant of a rejected row
+    pub error: Option<String>,
+}
+
+/// Every `AccountEvent` of a run, per client in the order they were applied
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    events: BTreeMap<u16, Vec<AccountEvent>>,
+}
+
+impl History {
+    pub(crate) fn extend(&mut self, events: impl IntoIterator<Item = AccountEvent>) {
+        for event in events {
+            self.events.entry(event.client_id).or_default().push(event);
+        }
+    }
+
+    /// Events of `client_id`, oldest first
+    pub fn events(&self, client_id: u16) -> &[AccountEvent] {
+        self.events.get(&client_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Funds of `client_id` in `currency` once every row up to and including `line` was applied
+    pub fn balance_at_line(&self, client_id: u16, currency: Currency, line: u64) -> Balance {
+        let events = self.events(client_id);
+        let end = events.partition_point(|x| x.line <= line);
+        events[..end]
+            .iter()
+            .rev()
+            .find(|x| x.currency == currency)
+            .map(|x| x.after)
+            .unwrap_or_default()
+    }
+
+    /// Funds of `client_id` in the currency of `tx_id` right before the row that brought
+    /// `tx_id` in was applied, or attempted if it was rejected. `None` if the client never
+    /// saw `tx_id`.
+    pub fn balance_before_tx(&self, client_id: u16, tx_id: u32) -> Option<Balance> {
+        self.events(client_id)
+            .iter()
+            .find(|x| x.tx_id == tx_id)
+            .map(|x| x.before)
+    }
+
+    /// Writes every event as a JSON line, sorted by client
+    pub fn write(&self, mut writer: impl Write) -> Result<(), RuntimeError> {
+        for event in self.events.values().flatten() {
+            serde_json::to_writer(&mut writer, event).map_err(history_error)?;
+            writer.write_all(b"\n").map_err(history_error)?;
+        }
+        writer.flush().map_err(history_error)
+    }
+
+    /// Events written as JSON lines, by `write` or a `HistoryWriter`
+    pub fn read(reader: impl BufRead) -> Result<Self, RuntimeError> {
+        let mut history = Self::default();
+        for line in reader.lines() {
+            let line = line.map_err(history_error)?;
+            if !line.is_empty() {
+                history.extend([serde_json::from_str(&line).map_err(history_error)?]);
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Writes `AccountEvent`s to a file as JSON lines as they are reported, so the history of
+/// a run never has to fit in memory. Every client's events keep their order.
+pub struct HistoryWriter {
+    file: BufWriter<File>,
+    offset: u64, // end of the last event written
+}
+
+impl HistoryWriter {
+    /// Opens `path`, created if missing. Events go after what it holds until a run
+    /// starts it over or a resume cuts it back to its checkpoint.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RuntimeError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .map_err(history_error)?;
+        let offset = file.seek(SeekFrom::End(0)).map_err(history_error)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            offset,
+        })
+    }
+
+    pub(crate) fn write(&mut self, events: &[AccountEvent]) -> Result<(), RuntimeError> {
+        for event in events {
+            let mut line = serde_json::to_vec(event).map_err(history_error)?;
+            line.push(b'\n');
+            self.file.write_all(&line).map_err(history_error)?;
+            self.offset += line.len() as u64;
+        }
+        self.file.flush().map_err(history_error)
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Drops every event written after `offset`
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<(), RuntimeError> {
+        self.file.flush().map_err(history_error)?;
+        let file = self.file.get_mut();
+        file.set_len(offset).map_err(history_error)?;
+        file.seek(SeekFrom::Start(offset)).map_err(history_error)?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+fn history_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string()))
+}
+
+/// Balances of the accounts a row touches, taken before it is applied so the events can
+/// be recorded afterwards
+pub(crate) struct Snapshot {
+    line: u64,
+    tx_id: u32,
+    transaction_type: CSVTransactionType,
+    currency: Currency,
+    before: Vec<(u16, BTreeMap<Currency, Balance>)>,
+}
+
+impl Snapshot {
+    pub(crate) fn take<'a>(
+        line: u64,
+        transaction: &CSVTransaction,
+        accounts: impl IntoIterator<Item = &'a ClientAccount>,
+    ) -> Self {
+        Self {
+            line,
+            tx_id: transaction.tx_id,
+            transaction_type: transaction.transaction_type,
+            currency: transaction.currency,
+            before: accounts
+                .into_iter()
+                .map(|x| (x.id, x.balances.clone()))
+                .collect(),
+        }
+    }
+
+    /// One event per account, in the currency `journal` moved money in
+    pub(crate) fn events<'a>(
+        self,
+        accounts: impl IntoIterator<Item = &'a ClientAccount>,
+        journal: &[JournalEntry],
+        result: &Result<(), RuntimeError>,
+    ) -> Vec<AccountEvent> {
+        let currency = journal.first().map_or(self.currency, |x| x.currency);
+        let error = result.as_ref().err().map(|e| {
+            let variant: &'static str = match e {
+                RuntimeError::Recoverable(x) | RuntimeError::NonRecoverable(x) => x.into(),
+            };
+            variant.to_string()
+        });
+        self.before
+            .into_iter()
+            .zip(accounts)
+            .map(|((client_id, before), account)| AccountEvent {
+                line: self.line,
+                tx_id: self.tx_id,
+                transaction_type: self.transaction_type,
+                client_id,
+                currency,
+                before: before.get(&currency).copied().unwrap_or_default(),
+                after: account.balance(currency),
+                error: error.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+
+    fn event(line: u64, tx_id: u32, currency: Currency, units: i64) -> AccountEvent {
+        AccountEvent {
+            line,
+            tx_id,
+            transaction_type: CSVTransactionType::Deposit,
+            client_id: 1,
+            currency,
+            before: Balance::default(),
+            after: Balance {
+                available: Amount::from_units(units),
+                held: Amount::ZERO,
+            },
+            error: None,
+        }
+    }
+
+    #[test]
+    fn balance_at_line_takes_the_last_event_in_the_currency() -> Result<(), RuntimeError> {
+        let (usd, eur) = (Currency::default(), "EUR".parse::<Currency>()?);
+        let mut history = History::default();
+        history.extend([
+            event(2, 1, usd, 10),
+            event(5, 2, eur, 7),
+            event(9, 3, usd, 30),
+        ]);
+        assert_eq!(history.balance_at_line(1, usd, 1), Balance::default());
+        assert_eq!(
+            history.balance_at_line(1, usd, 8).available,
+            Amount::from_units(10)
+        );
+        assert_eq!(
+            history.balance_at_line(1, eur, 8).available,
+            Amount::from_units(7)
+        );
+        assert_eq!(
+            history.balance_at_line(1, usd, 9).available,
+            Amount::from_units(30)
+        );
+        assert_eq!(history.balance_before_tx(1, 3), Some(Balance::default()));
+        assert_eq!(history.balance_before_tx(2, 3), None);
+        Ok(())
+    }
+}
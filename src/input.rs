@@ -0,0 +1,231 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use csv::StringRecord;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::runtime::Handle;
+
+/// Where the runner reads its CSV rows from.
+pub enum Input {
+    /// A file on disk. The only input a checkpoint can be resumed from.
+    Path(PathBuf),
+    Stdin,
+    /// Any blocking reader, consumed by the first run
+    Reader(Option<Box<dyn Read + Send>>),
+    /// Any async reader, consumed by the first run
+    AsyncReader(Option<Pin<Box<dyn AsyncRead + Send>>>),
+}
+
+impl Input {
+    /// `-` means stdin, like most command line tools
+    pub fn from_arg(arg: &str) -> Self {
+        match arg {
+            "-" => Input::Stdin,
+            x => Input::Path(PathBuf::from(x)),
+        }
+    }
+
+    pub fn from_reader(reader: impl Read + Send + 'static) -> Self {
+        Input::Reader(Some(Box::new(reader)))
+    }
+
+    pub fn from_async_reader(reader: impl AsyncRead + Send + 'static) -> Self {
+        Input::AsyncReader(Some(Box::pin(reader)))
+    }
+
+    /// Opens the input positioned at `start`, or at the first record after the headers.
+    /// Must be called from within the tokio runtime.
+    pub(crate) fn open(
+        &mut self,
+        start: Option<csv::Position>,
+    ) -> Result<BatchReader, RuntimeError> {
+        let reader: Box<dyn Read + Send> = match (self, &start) {
+            (Input::Path(path), _) => {
+                let mut file = File::open(path).map_err(read_error)?;
+                if let Some(position) = &start {
+                    file.seek(SeekFrom::Start(position.byte()))
+                        .map_err(read_error)?;
+                }
+                Box::new(file)
+            }
+            (_, Some(_)) => {
+                return Err(RuntimeError::NonRecoverable(
+                    RuntimeErrorType::CheckpointError("Only file input can be resumed".to_string()),
+                ))
+            }
+            (Input::Stdin, None) => Box::new(std::io::stdin()),
+            (Input::Reader(reader), None) => reader.take().ok_or_else(consumed_error)?,
+            (Input::AsyncReader(reader), None) => Box::new(BlockingAsyncReader {
+                inner: reader.take().ok_or_else(consumed_error)?,
+                handle: Handle::current(),
+            }),
+        };
+        let records = csv::ReaderBuilder::new()
+            .has_headers(start.is_none())
+            .flexible(true) // rows may leave out the reason, destination, currency and timestamp columns
+            .from_reader(reader)
+            .into_records();
+        Ok(BatchReader {
+            records: Some(records),
+            unchecked_header: start.is_none(),
+            start: start.unwrap_or_else(csv::Position::new),
+            lines_before: 0,
+            last_line: 0,
+        })
+    }
+}
+
+impl From<PathBuf> for Input {
+    fn from(path: PathBuf) -> Self {
+        Input::Path(path)
+    }
+}
+
+/// Columns in the order `CSVTransaction` reads them, with the names a header may give
+/// each. An input can stop after any of them but can't leave one out or reorder them.
+const COLUMNS: [&[&str]; 7] = [
+    &["type"],
+    &["client"],
+    &["tx"],
+    &["amount"],
+    &["reason", "destination"],
+    &["currency"],
+    &["timestamp"],
+];
+
+/// Refuses a header naming its columns in another order than they are read in, rather
+/// than reading e.g. a timestamp as the currency. A first line not starting with `type`
+/// is no header and skipped like one, as it always was.
+fn check_columns(header: &StringRecord) -> Result<(), RuntimeError> {
+    if header.get(0).map(str::trim) != Some("type") {
+        return Ok(());
+    }
+    if header.len() > COLUMNS.len() {
+        return Err(header_error(format!(
+            "the header has {} columns, at most {} are read",
+            header.len(),
+            COLUMNS.len()
+        )));
+    }
+    for (column, names) in header.iter().zip(COLUMNS) {
+        let column = column.trim();
+        if !names.contains(&column) {
+            return Err(header_error(format!(
+                "the header has {:?} where {} is read",
+                column,
+                names.join(" or ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn header_error(message: String) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVLineParseError(message))
+}
+
+/// Reads the input a batch of records at a time on a blocking thread, so a slow or
+/// unbounded source neither stalls the runtime nor has to fit in memory.
+pub(crate) struct BatchReader {
+    records: Option<csv::StringRecordsIntoIter<Box<dyn Read + Send>>>,
+    start: csv::Position, // where the underlying reader started in the whole input
+    unchecked_header: bool, // only an input read from its start has a header
+    lines_before: u64,    // added to every line, see `after_line`
+    last_line: u64,
+}
+
+impl BatchReader {
+    /// Numbers the lines as if the input came after `line`, e.g. the earlier ones of
+    /// a stream fed in pieces. Positions are still those in this input.
+    pub(crate) fn after_line(mut self, line: u64) -> Self {
+        self.lines_before = line;
+        self.last_line = line;
+        self
+    }
+
+    /// The line of the last record read so far
+    pub(crate) fn last_line(&self) -> u64 {
+        self.last_line
+    }
+
+    /// Returns up to `size` records with the input line each starts on, empty once the
+    /// input is exhausted
+    pub(crate) async fn next_batch(
+        &mut self,
+        size: usize,
+    ) -> Result<Vec<(u64, csv::Result<StringRecord>)>, RuntimeError> {
+        let mut records = self.records.take().ok_or_else(consumed_error)?;
+        let line_offset = self.lines_before + self.start.line() - 1;
+        let check_header = std::mem::take(&mut self.unchecked_header);
+        let (batch, records) = tokio::task::spawn_blocking(move || {
+            if check_header {
+                if let Err(e) = records
+                    .reader_mut()
+                    .headers()
+                    .map_err(read_error)
+                    .and_then(check_columns)
+                {
+                    return (Err(e), records);
+                }
+            }
+            let mut batch = Vec::with_capacity(size);
+            for result in records.by_ref().take(size) {
+                let relative_line = match &result {
+                    Ok(record) => record.position().map(csv::Position::line),
+                    Err(e) => e.position().map(csv::Position::line),
+                };
+                let line = line_offset + relative_line.unwrap_or_default();
+                batch.push((line, result));
+            }
+            (Ok(batch), records)
+        })
+        .await
+        .map_err(|e| RuntimeError::NonRecoverable(RuntimeErrorType::JoinError(e)))?;
+        self.records = Some(records);
+        let batch = batch?;
+        if let Some((line, _)) = batch.last() {
+            self.last_line = *line;
+        }
+        Ok(batch)
+    }
+
+    /// Position of the next record in the whole input
+    pub(crate) fn position(&self) -> csv::Position {
+        let relative = self
+            .records
+            .as_ref()
+            .expect("Only taken while a batch is read")
+            .reader()
+            .position();
+        let mut position = csv::Position::new();
+        position
+            .set_byte(self.start.byte() + relative.byte())
+            .set_line(self.start.line() + relative.line() - 1)
+            .set_record(self.start.record() + relative.record());
+        position
+    }
+}
+
+/// Lets the csv reader pull from an async source. Only used from blocking threads.
+struct BlockingAsyncReader {
+    inner: Pin<Box<dyn AsyncRead + Send>>,
+    handle: Handle,
+}
+
+impl Read for BlockingAsyncReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.handle.block_on(self.inner.read(buf))
+    }
+}
+
+fn read_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string()))
+}
+
+fn consumed_error() -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(
+        "Input was already consumed".to_string(),
+    ))
+}
@@ -0,0 +1,247 @@
+use crate::amount::Amount;
+use crate::client_account::ClientAccount;
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::report::{RecordWriter, ReportFormat};
+use crate::transaction::CSVTransactionType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Account of the general ledger. Client accounts are liabilities, so their ledger
+/// balance is the negated balance of the `ClientAccount`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LedgerAccount {
+    /// `client-available:<client>`
+    ClientAvailable(u16),
+    /// `client-held:<client>`
+    ClientHeld(u16),
+    /// Money entering and leaving through deposits, withdrawals and chargebacks of deposits
+    ExternalCash,
+    /// Withdrawals claimed by an open dispute, and reversed ones once charged back
+    ChargebackLoss,
+    /// Fees charged, less the ones refunded
+    FeeIncome,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::ClientAvailable(x) => write!(f, "client-available:{}", x),
+            LedgerAccount::ClientHeld(x) => write!(f, "client-held:{}", x),
+            LedgerAccount::ExternalCash => f.write_str("external-cash"),
+            LedgerAccount::ChargebackLoss => f.write_str("chargeback-loss"),
+            LedgerAccount::FeeIncome => f.write_str("fee-income"),
+        }
+    }
+}
+
+impl FromStr for LedgerAccount {
+    type Err = RuntimeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let client = |x: &str| {
+            u16::from_str(x).map_err(|_| {
+                RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(format!(
+                    "Ledger account {:?}",
+                    s
+                )))
+            })
+        };
+        match s.split_once(':') {
+            Some(("client-available", x)) => Ok(LedgerAccount::ClientAvailable(client(x)?)),
+            Some(("client-held", x)) => Ok(LedgerAccount::ClientHeld(client(x)?)),
+            _ => match s {
+                "external-cash" => Ok(LedgerAccount::ExternalCash),
+                "chargeback-loss" => Ok(LedgerAccount::ChargebackLoss),
+                "fee-income" => Ok(LedgerAccount::FeeIncome),
+                _ => Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+                    format!("Ledger account {:?}", s),
+                ))),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for LedgerAccount {
+    type Error = RuntimeError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        LedgerAccount::from_str(&value)
+    }
+}
+
+impl From<LedgerAccount> for String {
+    fn from(value: LedgerAccount) -> Self {
+        value.to_string()
+    }
+}
+
+/// `amount` moved from `credit` to `debit` by the row `tx_id`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub tx_id: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: CSVTransactionType,
+    pub currency: Currency,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    #[serde(with = "crate::amount::decimal")]
+    pub amount: Amount,
+}
+
+/// Balance of every ledger account per currency, debits counted positive
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ledger {
+    balances: BTreeMap<Currency, BTreeMap<LedgerAccount, Amount>>,
+}
+
+impl Ledger {
+    pub(crate) fn post(&mut self, entry: &JournalEntry) -> Result<(), RuntimeError> {
+        let balances = self.balances.entry(entry.currency).or_default();
+        let debit = balances.entry(entry.debit).or_default();
+        *debit = debit.try_add(entry.amount)?;
+        let credit = balances.entry(entry.credit).or_default();
+        *credit = credit.try_sub(entry.amount)?;
+        Ok(())
+    }
+
+    /// Zero for accounts nothing was posted to
+    pub fn balance(&self, account: LedgerAccount, currency: Currency) -> Amount {
+        self.balances
+            .get(&currency)
+            .and_then(|x| x.get(&account))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Checks that every currency sums to zero and that the client accounts of the ledger
+    /// match `accounts`. Fails with a `NonRecoverable` `LedgerImbalance` error otherwise.
+    pub fn verify<'a>(
+        &self,
+        accounts: impl IntoIterator<Item = &'a ClientAccount>,
+    ) -> Result<(), RuntimeError> {
+        for (currency, balances) in &self.balances {
+            let sum = balances
+                .values()
+                .try_fold(Amount::ZERO, |sum, x| sum.try_add(*x))?;
+            if sum != Amount::ZERO {
+                return Err(imbalance_error(format!(
+                    "{} ledger sums to {}",
+                    currency, sum
+                )));
+            }
+        }
+        for account in accounts {
+            for (currency, balance) in account.balances() {
+                for (ledger_account, expected) in [
+                    (
+                        LedgerAccount::ClientAvailable(account.id),
+                        balance.available,
+                    ),
+                    (LedgerAccount::ClientHeld(account.id), balance.held),
+                ] {
+                    let found = Amount::ZERO.try_sub(self.balance(ledger_account, currency))?;
+                    if found != expected {
+                        return Err(imbalance_error(format!(
+                            "{} {} is {} in the ledger but {} on the account",
+                            ledger_account, currency, found, expected
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn imbalance_error(message: String) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::LedgerImbalance(message))
+}
+
+/// Side output listing every journal entry
+pub struct JournalWriter {
+    writer: RecordWriter,
+}
+
+const JOURNAL_HEADER: &[&str] = &["tx_id", "type", "currency", "debit", "credit", "amount"];
+
+impl JournalWriter {
+    pub fn create(path: impl AsRef<Path>, format: ReportFormat) -> Result<Self, RuntimeError> {
+        let file = File::create(path).map_err(journal_error)?;
+        Ok(Self::from_writer(BufWriter::new(file), format))
+    }
+
+    /// Keeps what `path` holds, so a resumed run can cut it back to its checkpoint and
+    /// carry on. The csv header is only written to a new or empty file.
+    pub fn open(path: impl AsRef<Path>, format: ReportFormat) -> Result<Self, RuntimeError> {
+        Ok(Self {
+            writer: RecordWriter::open(path, format, JOURNAL_HEADER)?,
+        })
+    }
+
+    pub fn from_writer(writer: impl Write + Send + Sync + 'static, format: ReportFormat) -> Self {
+        Self {
+            writer: RecordWriter::new(writer, format, JOURNAL_HEADER),
+        }
+    }
+
+    pub(crate) fn write(&mut self, entries: &[JournalEntry]) -> Result<(), RuntimeError> {
+        self.writer.write(entries)
+    }
+
+    pub(crate) fn offset(&self) -> Result<Option<u64>, RuntimeError> {
+        self.writer.offset()
+    }
+
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<(), RuntimeError> {
+        self.writer.truncate(offset)
+    }
+}
+
+fn journal_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledger_accounts_round_trip_as_strings() -> Result<(), RuntimeError> {
+        for account in [
+            LedgerAccount::ClientAvailable(7),
+            LedgerAccount::ClientHeld(u16::MAX),
+            LedgerAccount::ExternalCash,
+            LedgerAccount::ChargebackLoss,
+            LedgerAccount::FeeIncome,
+        ] {
+            assert_eq!(LedgerAccount::from_str(&account.to_string())?, account);
+        }
+        assert!(LedgerAccount::from_str("client-held:x").is_err());
+
+        let mut ledger = Ledger::default();
+        ledger.post(&JournalEntry {
+            tx_id: 1,
+            transaction_type: CSVTransactionType::Deposit,
+            currency: Currency::default(),
+            debit: LedgerAccount::ExternalCash,
+            credit: LedgerAccount::ClientAvailable(7),
+            amount: Amount::from_units(10_000),
+        })?;
+        let json = serde_json::to_string(&ledger).map_err(|e| {
+            RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(e.to_string()))
+        })?;
+        assert_eq!(
+            json,
+            r#"{"balances":{"USD":{"client-available:7":-10000,"external-cash":10000}}}"#
+        );
+        Ok(())
+    }
+}
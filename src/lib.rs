@@ -0,0 +1,58 @@
+//! Applies deposits, withdrawals, transfers, disputes, resolves and chargebacks to client accounts,
+//! along with admin unlock, freeze and unfreeze rows.
+//!
+//! `Runner` processes a whole csv `Input` in batches, with checkpoints, rejection reports
+//! and an `ErrorPolicy`. `Engine` takes `CSVTransaction`s one at a time for services that
+//! feed transactions in process. Both are set up from an `EngineConfig`, the stores behind
+//! them stay internal. `HttpServer` and `FeedServer` keep a `Runner` going for
+//! transactions sent over HTTP or plain TCP. The `rust-test` binary is a thin command line on top.
+
+mod amount;
+mod audit;
+mod checkpoint;
+mod client_account;
+mod client_actor;
+mod config;
+mod constants;
+mod currency;
+mod engine;
+mod error;
+mod fee;
+mod feed;
+mod history;
+mod input;
+mod ledger;
+mod limits;
+mod output;
+mod policy;
+mod report;
+mod runner;
+mod scratch;
+mod server;
+mod store;
+mod transaction;
+
+pub use amount::{Amount, AMOUNT_SCALE};
+pub use audit::{AdminAction, AuditRecord};
+pub use client_account::{Balance, ClientAccount};
+pub use config::EngineConfig;
+pub use currency::Currency;
+pub use engine::Engine;
+pub use error::{RuntimeError, RuntimeErrorType};
+pub use fee::{FeeSchedule, FeeTier};
+pub use feed::FeedServer;
+pub use history::{AccountEvent, History, HistoryWriter};
+pub use input::Input;
+pub use ledger::{JournalEntry, JournalWriter, Ledger, LedgerAccount};
+pub use limits::WithdrawalLimits;
+pub use output::OutputFormat;
+pub use policy::{DisputePolicy, ErrorPolicy, ExpiredDisputePolicy, FeePolicy};
+pub use report::{Rejection, RejectionReport, ReportFormat};
+pub use runner::Runner;
+pub use server::HttpServer;
+pub use store::StoreBackend;
+pub use transaction::{
+    CSVTransaction, CSVTransactionType, ChargeBackRequest, DepositRequest, DisputeRequest,
+    FreezeRequest, ResolveRequest, TransferRequest, UnfreezeRequest, UnlockRequest,
+    WithdrawalRequest,
+};
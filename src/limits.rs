@@ -0,0 +1,168 @@
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Risk rules every withdrawal is checked against, per client. Amounts are in the currency
+/// of the withdrawal, `max_count` counts withdrawals in every currency. The rolling window is a number of rows:
+/// the withdrawal being checked and the `window - 1` rows of the same client before it.
+/// ```toml
+/// [limits]
+/// max_amount = "1000"
+/// window = 100
+/// max_count = 5
+/// max_sum = "2500"
+/// min_balance = "10"
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WithdrawalLimits {
+    /// Largest single withdrawal
+    #[serde(with = "crate::amount::decimal::optional")]
+    pub max_amount: Option<Amount>,
+    /// Rows of the client `max_count` and `max_sum` look back over
+    pub window: Option<u32>,
+    /// Withdrawals allowed within the window
+    pub max_count: Option<u32>,
+    /// Withdrawn amount allowed within the window, fees not included
+    #[serde(with = "crate::amount::decimal::optional")]
+    pub max_sum: Option<Amount>,
+    /// What has to stay available after a withdrawal and its fee
+    #[serde(with = "crate::amount::decimal::optional")]
+    pub min_balance: Option<Amount>,
+}
+
+impl WithdrawalLimits {
+    /// `max_count` and `max_sum` need a window
+    pub fn validate(&self) -> Result<(), RuntimeError> {
+        let rolling = self.max_count.is_some() || self.max_sum.is_some();
+        match self.window {
+            Some(0) => Err(config_error("limits.window must be at least 1")),
+            None if rolling => Err(config_error(
+                "limits.max_count and limits.max_sum need limits.window",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Withdrawals of one client within the rolling window, kept on the account so
+/// checkpoints carry it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Velocity {
+    rows: u64,                                      // rows of the client seen so far
+    withdrawals: VecDeque<(u64, Currency, Amount)>, // oldest first
+}
+
+impl Velocity {
+    /// Moves the window on by one row of the client, whatever the row does
+    pub(crate) fn next_row(&mut self) {
+        self.rows += 1;
+    }
+
+    /// Checks a withdrawal of `amount` made in the current row. `available` is what
+    /// is left in `currency` once it and its fee went through.
+    pub(crate) fn check(
+        &mut self,
+        limits: &WithdrawalLimits,
+        currency: Currency,
+        amount: Amount,
+        available: Amount,
+    ) -> Result<(), RuntimeError> {
+        if let Some(window) = limits.window {
+            let first_row = self.rows.saturating_sub(u64::from(window));
+            while matches!(self.withdrawals.front(), Some((row, _, _)) if *row <= first_row) {
+                self.withdrawals.pop_front();
+            }
+        }
+        if let Some(max) = limits.max_amount {
+            if amount > max {
+                return Err(limit_error(format!(
+                    "Withdrawal of {} is above the limit of {}",
+                    amount, max
+                )));
+            }
+        }
+        if let Some(max) = limits.max_count {
+            if self.withdrawals.len() >= max as usize {
+                return Err(limit_error(format!(
+                    "{} withdrawals within the last {} rows already",
+                    self.withdrawals.len(),
+                    limits.window.unwrap_or_default()
+                )));
+            }
+        }
+        if let Some(max) = limits.max_sum {
+            let sum = self
+                .withdrawals
+                .iter()
+                .filter(|(_, c, _)| *c == currency)
+                .try_fold(amount, |sum, (_, _, x)| sum.try_add(*x))?;
+            if sum > max {
+                return Err(limit_error(format!(
+                    "Withdrawals of {} {} within the last {} rows are above the limit of {}",
+                    sum,
+                    currency,
+                    limits.window.unwrap_or_default(),
+                    max
+                )));
+            }
+        }
+        if let Some(min) = limits.min_balance {
+            if available < min {
+                return Err(limit_error(format!(
+                    "Withdrawal leaves {} available, below the minimum of {}",
+                    available, min
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a withdrawal that went through, if any window needs it
+    pub(crate) fn record(&mut self, limits: &WithdrawalLimits, currency: Currency, amount: Amount) {
+        if limits.window.is_some() {
+            self.withdrawals.push_back((self.rows, currency, amount));
+        }
+    }
+}
+
+fn limit_error(message: String) -> RuntimeError {
+    RuntimeError::Recoverable(RuntimeErrorType::LimitExceeded(message))
+}
+
+fn config_error(message: &str) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ConfigError(message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_counts_rows_of_the_client() {
+        let limits = WithdrawalLimits {
+            window: Some(3),
+            max_count: Some(1),
+            ..WithdrawalLimits::default()
+        };
+        let (usd, amount) = (Currency::default(), Amount::from_units(10_000));
+        let mut velocity = Velocity::default();
+        velocity.next_row();
+        assert!(velocity.check(&limits, usd, amount, Amount::ZERO).is_ok());
+        velocity.record(&limits, usd, amount);
+        for _ in 0..2 {
+            velocity.next_row();
+            assert!(matches!(
+                velocity.check(&limits, usd, amount, Amount::ZERO),
+                Err(RuntimeError::Recoverable(RuntimeErrorType::LimitExceeded(
+                    _
+                )))
+            ));
+        }
+        // the first withdrawal left the window
+        velocity.next_row();
+        assert!(velocity.check(&limits, usd, amount, Amount::ZERO).is_ok());
+    }
+}
@@ -1,44 +1,265 @@
-use crate::runner::Runner;
+use log::LevelFilter;
+use rust_test::{
+    DisputePolicy, EngineConfig, ErrorPolicy, ExpiredDisputePolicy, FeePolicy, FeedServer,
+    HistoryWriter, HttpServer, Input, JournalWriter, OutputFormat, RejectionReport, ReportFormat,
+    Runner, StoreBackend,
+};
 use std::env;
 use std::error::Error;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-mod client_account;
-mod constants;
-mod error;
-mod runner;
-mod transaction;
+/// Writes log records to stderr, stdout carries the accounts
+struct StderrLogger;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn main() -> Result<(), Box<dyn Error>> {
     let command_line_args: Vec<String> = env::args().collect();
-    let input_csv_file_path = PathBuf::from(&command_line_args[1]);
-    let mut runner = Runner::new(input_csv_file_path);
-    runner.run().await?;
-    runner.print_all_accounts().await;
+    let input = match command_line_args.get(1) {
+        Some(x) => x,
+        None => {
+            eprintln!("Usage: rust-test <input.csv | - | serve | feed> [flags]");
+            std::process::exit(2);
+        }
+    };
+    let flags = command_line_args.get(2..).unwrap_or_default();
+    let config = engine_config(flags)?;
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(config.log_level);
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(workers) = config.workers {
+        runtime.worker_threads(workers);
+    }
+    runtime.build()?.block_on(run(input, flags, config))
+}
+
+fn has_flag(flags: &[String], flag: &str) -> bool {
+    flags.iter().any(|x| x == flag)
+}
+
+fn flag_value<'a>(flags: &'a [String], flag: &str) -> Option<&'a String> {
+    flags
+        .iter()
+        .position(|x| x == flag)
+        .and_then(|i| flags.get(i + 1))
+}
+
+/// `--config PATH` or the defaults, with every other setting flag on top
+fn engine_config(flags: &[String]) -> Result<EngineConfig, Box<dyn Error>> {
+    let mut config = match flag_value(flags, "--config") {
+        Some(path) => EngineConfig::load(path)?,
+        None => EngineConfig::default(),
+    };
+    if let Some(x) = flag_value(flags, "--batch-size") {
+        config.batch_size = x.parse()?;
+    }
+    if let Some(x) = flag_value(flags, "--scratch-root") {
+        config.scratch_root = PathBuf::from(x);
+    }
+    if let Some(x) = flag_value(flags, "--store") {
+        config.store = StoreBackend::from_str(x)?;
+    }
+    if has_flag(flags, "--in-memory") {
+        config.store = StoreBackend::Memory;
+    }
+    if has_flag(flags, "--log-store") {
+        config.store = StoreBackend::Log;
+    }
+    if has_flag(flags, "--retain-scratch") {
+        config.retain_scratch = true;
+    }
+    if let Some(x) = flag_value(flags, "--workers") {
+        config.workers = Some(x.parse()?);
+    }
+    if let Some(x) = flag_value(flags, "--output-format") {
+        config.output_format = OutputFormat::from_str(x)?;
+    }
+    if let Some(x) = flag_value(flags, "--output-scale") {
+        config.output_scale = x.parse()?;
+    }
+    if let Some(x) = flag_value(flags, "--error-policy") {
+        config.error_policy = ErrorPolicy::from_str(x)?;
+    }
+    if let Some(x) = flag_value(flags, "--dispute-policy") {
+        config.dispute_policy = DisputePolicy::from_str(x)?;
+    }
+    if let Some(x) = flag_value(flags, "--max-dispute-cycles") {
+        config.max_dispute_cycles = x.parse()?;
+    }
+    if let Some(x) = flag_value(flags, "--fee-policy") {
+        config.fee_policy = FeePolicy::from_str(x)?;
+    }
+    if let Some(x) = flag_value(flags, "--dispute-window-days") {
+        config.dispute_window_days = Some(x.parse()?);
+    }
+    if let Some(x) = flag_value(flags, "--dispute-deadline-days") {
+        config.dispute_deadline_days = Some(x.parse()?);
+    }
+    if let Some(x) = flag_value(flags, "--expired-disputes") {
+        config.expired_disputes = ExpiredDisputePolicy::from_str(x)?;
+    }
+    if has_flag(flags, "--frozen-account-disputes") {
+        config.frozen_account_disputes = true;
+    }
+    if let Some(x) = flag_value(flags, "--log-level") {
+        config.log_level = LevelFilter::from_str(x)?;
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+/// Runner over `input` with the store, reports and journal the flags ask for
+fn build_runner(
+    input: Input,
+    flags: &[String],
+    config: &EngineConfig,
+) -> Result<Runner, Box<dyn Error>> {
+    let flag_value = |flag: &str| flag_value(flags, flag);
+
+    let resuming = flag_value("--resume").is_some();
+    let mut runner = match flag_value("--resume") {
+        Some(directory) => Runner::open(input, directory, config)?,
+        None => Runner::new(input, config)?,
+    };
+    if let Some(path) = flag_value("--rejections") {
+        let format = match flag_value("--rejections-format") {
+            Some(x) => ReportFormat::from_str(x)?,
+            None => ReportFormat::Csv,
+        };
+        let report = match resuming {
+            true => RejectionReport::open(path, format)?,
+            false => RejectionReport::create(path, format)?,
+        };
+        runner = runner.with_rejection_report(report);
+    }
+    if let Some(path) = flag_value("--journal") {
+        let format = match flag_value("--journal-format") {
+            Some(x) => ReportFormat::from_str(x)?,
+            None => ReportFormat::Csv,
+        };
+        let journal = match resuming {
+            true => JournalWriter::open(path, format)?,
+            false => JournalWriter::create(path, format)?,
+        };
+        runner = runner.with_journal(journal);
+    }
+    if let Some(path) = flag_value("--history") {
+        runner = runner.with_history(HistoryWriter::open(path)?);
+    }
+    Ok(runner)
+}
+
+/// `serve [--listen ADDR]`: takes transactions over HTTP until interrupted, on
+/// 127.0.0.1:8080 by default
+async fn serve(flags: &[String], config: EngineConfig) -> Result<(), Box<dyn Error>> {
+    let runner = build_runner(Input::from_reader(std::io::empty()), flags, &config)?;
+    let listen = flag_value(flags, "--listen").map_or("127.0.0.1:8080", String::as_str);
+    let listener = std::net::TcpListener::bind(listen)?;
+    log::info!("Serving on {}", listener.local_addr()?);
+    HttpServer::new(runner)
+        .with_output(config.output_format, config.output_scale)
+        .serve(listener, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// `feed [--listen ADDR]`: takes csv lines over TCP until interrupted, on 127.0.0.1:7070
+/// by default
+async fn feed(flags: &[String], config: EngineConfig) -> Result<(), Box<dyn Error>> {
+    let runner = build_runner(Input::from_reader(std::io::empty()), flags, &config)?;
+    let listen = flag_value(flags, "--listen").map_or("127.0.0.1:7070", String::as_str);
+    let listener = std::net::TcpListener::bind(listen)?;
+    log::info!("Taking the feed on {}", listener.local_addr()?);
+    FeedServer::new(runner)
+        .serve(listener, async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+async fn run(input: &str, flags: &[String], config: EngineConfig) -> Result<(), Box<dyn Error>> {
+    match input {
+        "serve" => return serve(flags, config).await,
+        "feed" => return feed(flags, config).await,
+        _ => {}
+    }
+    let has_flag = |flag: &str| has_flag(flags, flag);
+    let flag_value = |flag: &str| flag_value(flags, flag);
+    let mut runner = build_runner(Input::from_arg(input), flags, &config)?;
+    let result = if has_flag("--resume") {
+        runner.resume().await
+    } else {
+        runner.run().await
+    };
+    if let Err(e) = result {
+        if let Some(directory) = runner.scratch_directory() {
+            eprintln!(
+                "Run failed, continue it with --resume {}",
+                directory.display()
+            );
+        }
+        return Err(e.into());
+    }
+    let format = config.output_format;
+    let scale = config.output_scale;
+    match flag_value("--output") {
+        Some(path) => {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            runner.write_accounts(file, format, scale).await?
+        }
+        None => {
+            runner
+                .write_accounts(std::io::stdout().lock(), format, scale)
+                .await?
+        }
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bigdecimal::{BigDecimal, FromPrimitive};
-    use std::process::{Command, Stdio};
-    use std::fs::{File};
-    use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+    use rust_test::Amount;
     use std::fs;
+    use std::fs::File;
+    use std::os::unix::prelude::{FromRawFd, IntoRawFd};
+    use std::process::{Command, Stdio};
+
+    fn amount(x: &str) -> Amount {
+        Amount::from_str(x).expect("valid amount")
+    }
 
     #[tokio::test]
     async fn sample_test() -> Result<(), Box<dyn Error>> {
         let input_csv_file_path = PathBuf::from("data/sample.csv");
-        let mut runner = Runner::new(input_csv_file_path);
+        let mut runner = Runner::new(input_csv_file_path, &EngineConfig::default())?;
         runner.run().await?;
         let x = runner.get_cloned_account_snapshot(1).await;
         let y = runner.get_cloned_account_snapshot(2).await;
-        assert_eq!(x.as_ref().unwrap().available, BigDecimal::from_f64(100.5).unwrap());
-        assert_eq!(x.as_ref().unwrap().held, BigDecimal::from(0));
-        assert_eq!(y.as_ref().unwrap().available, BigDecimal::from(1));
-        assert_eq!(y.as_ref().unwrap().held, BigDecimal::from(0));
+        assert_eq!(x.as_ref().unwrap().available(), amount("100.5"));
+        assert_eq!(x.as_ref().unwrap().held(), amount("0"));
+        assert_eq!(y.as_ref().unwrap().available(), amount("1"));
+        assert_eq!(y.as_ref().unwrap().held(), amount("0"));
         Ok(())
     }
 
@@ -53,14 +274,13 @@ mod tests {
 
         for _ in 0..5 {
             let input_csv_file_path = PathBuf::from("output.csv");
-            let mut runner = Runner::new(input_csv_file_path);
+            let mut runner = Runner::new(input_csv_file_path, &EngineConfig::default())?;
             runner.run().await?;
             let x = runner.get_cloned_account_snapshot(1).await;
-            assert_eq!(x.as_ref().unwrap().available, BigDecimal::from(10499)); // Since 1st line is ignored
+            assert_eq!(x.as_ref().unwrap().available(), amount("10499")); // Since 1st line is ignored
         }
 
         fs::remove_file("output.csv")?;
         Ok(())
     }
-
 }
@@ -0,0 +1,116 @@
+use crate::amount::Amount;
+use crate::client_account::{Balance, ClientAccount};
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumString,
+    strum::Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputFormat {
+    /// `client,currency,available,held,total,locked` rows sorted by client id and currency
+    #[default]
+    Csv,
+    /// One array holding every account
+    Json,
+    /// One account object per line
+    #[serde(rename = "jsonl")]
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+}
+
+/// One line of output per client and currency. Amounts are strings so the fixed scale
+/// survives in JSON too.
+#[derive(Serialize, Debug)]
+struct AccountRow {
+    client: u16,
+    currency: Currency,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl AccountRow {
+    fn new(
+        account: &ClientAccount,
+        currency: Currency,
+        balance: Balance,
+        scale: usize,
+    ) -> Result<Self, RuntimeError> {
+        let render = |amount: Amount| amount.to_string_with_scale(scale);
+        Ok(Self {
+            client: account.id(),
+            currency,
+            available: render(balance.available),
+            held: render(balance.held),
+            total: render(balance.total()?),
+            locked: account.locked(),
+        })
+    }
+
+    /// One row per currency the client used, a zero row in the default currency if none
+    fn for_account(account: &ClientAccount, scale: usize) -> Result<Vec<Self>, RuntimeError> {
+        let mut balances: Vec<(Currency, Balance)> = account.balances().collect();
+        if balances.is_empty() {
+            balances.push((Currency::default(), Balance::default()));
+        }
+        balances
+            .into_iter()
+            .map(|(currency, balance)| Self::new(account, currency, balance, scale))
+            .collect()
+    }
+}
+
+/// Writes `accounts` in `format`, one row per client and currency sorted by both, amounts
+/// with `scale` decimal places
+pub(crate) fn write_accounts(
+    accounts: &mut [ClientAccount],
+    writer: impl Write,
+    format: OutputFormat,
+    scale: usize,
+) -> Result<(), RuntimeError> {
+    accounts.sort_by_key(|account| account.id);
+    let mut rows = vec![];
+    for account in accounts.iter() {
+        rows.extend(AccountRow::for_account(account, scale)?);
+    }
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for row in rows {
+                writer.serialize(row).map_err(output_error)?;
+            }
+            writer.flush().map_err(output_error)
+        }
+        OutputFormat::Json => {
+            let mut writer = writer;
+            serde_json::to_writer_pretty(&mut writer, &rows).map_err(output_error)?;
+            writer.write_all(b"\n").map_err(output_error)
+        }
+        OutputFormat::JsonLines => {
+            let mut writer = writer;
+            for row in rows {
+                serde_json::to_writer(&mut writer, &row).map_err(output_error)?;
+                writer.write_all(b"\n").map_err(output_error)?;
+            }
+            writer.flush().map_err(output_error)
+        }
+    }
+}
+
+fn output_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string()))
+}
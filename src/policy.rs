@@ -0,0 +1,137 @@
+use crate::constants::DEFAULT_MAX_DISPUTE_CYCLES;
+
+/// What happens when applying a transaction fails with a `NonRecoverable` error
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Stop the whole run with a `TransactionFailed` error
+    #[default]
+    Halt,
+    /// Reject the transaction and every later one for the same client
+    Freeze,
+    /// Reject the transaction and carry on with the client
+    Skip,
+}
+
+/// How disputes are handled, handed to every dispute
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DisputeRules {
+    pub policy: DisputePolicy,
+    /// How often one transaction can be disputed, resolved disputes included. A
+    /// transaction that reached the limit can't be disputed again once resolved.
+    pub max_cycles: u32,
+    /// Whether disputes, resolves and chargebacks of existing transactions still go
+    /// through on a frozen account. Everything else stays rejected until it is unfrozen.
+    pub frozen_accounts: bool,
+    /// What a chargeback does with the fee the transaction paid
+    pub fee_policy: FeePolicy,
+    /// Seconds after a transaction it can still be disputed. Only checked when both the
+    /// transaction and the dispute have a timestamp.
+    pub window: Option<u64>,
+    /// Seconds a dispute can stay open before `expired` settles it
+    pub deadline: Option<u64>,
+    pub expired: ExpiredDisputePolicy,
+}
+
+impl Default for DisputeRules {
+    fn default() -> Self {
+        Self {
+            policy: DisputePolicy::default(),
+            max_cycles: DEFAULT_MAX_DISPUTE_CYCLES,
+            frozen_accounts: false,
+            fee_policy: FeePolicy::default(),
+            window: None,
+            deadline: None,
+            expired: ExpiredDisputePolicy::default(),
+        }
+    }
+}
+
+/// Which transactions a dispute may refer to, and what disputing a withdrawal does.
+/// Disputing a deposit is the same under every policy: dispute moves the amount from
+/// available to held, resolve moves it back and chargeback removes it and locks the account.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum DisputePolicy {
+    /// Only deposits can be disputed. A dispute of a withdrawal is rejected on its own
+    /// and changes nothing.
+    #[default]
+    DepositsOnly,
+    /// A disputed withdrawal is a claim that it should not have happened. Dispute holds
+    /// the amount without touching available, resolve drops the claim and chargeback
+    /// reverses the withdrawal: the amount goes back to available and the account locks.
+    WithdrawalReversal,
+    /// A dispute of a withdrawal is rejected on its own as a `Recoverable`
+    /// `DisputeRejected` error, which tells it apart from a dispute of a missing transaction
+    RejectWithdrawals,
+}
+
+/// What a chargeback does with the fee charged on the transaction it reverses
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum FeePolicy {
+    /// The fee stays charged
+    #[default]
+    Keep,
+    /// The fee goes back to available along with the chargeback
+    Refund,
+}
+
+/// How a dispute left open past its deadline is settled
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ExpiredDisputePolicy {
+    /// As if a resolve row came in, the claim is dropped
+    #[default]
+    Resolve,
+    /// As if a chargeback row came in, which locks the account
+    Chargeback,
+}
@@ -0,0 +1,208 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// A row that was not applied and why
+#[derive(Serialize, Debug, Clone)]
+pub struct Rejection {
+    pub line: u64,
+    pub record: String,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl Rejection {
+    pub(crate) fn new(source: &SourceRecord, error: &RuntimeErrorType) -> Self {
+        Self {
+            line: source.line,
+            record: source.raw.clone(),
+            error: error.into(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Where a parsed transaction came from, kept around for the rejection report
+#[derive(Debug, Clone)]
+pub(crate) struct SourceRecord {
+    pub(crate) line: u64,
+    pub(crate) raw: String,
+}
+
+impl SourceRecord {
+    pub(crate) fn new(line: u64, record: &csv::StringRecord) -> Self {
+        Self {
+            line,
+            raw: record.iter().collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ReportFormat {
+    Csv,
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+}
+
+/// Side output listing every rejected row
+pub struct RejectionReport {
+    writer: RecordWriter,
+}
+
+const REPORT_HEADER: &[&str] = &["line", "record", "error", "message"];
+
+impl RejectionReport {
+    pub fn create(path: impl AsRef<Path>, format: ReportFormat) -> Result<Self, RuntimeError> {
+        let file = File::create(path).map_err(report_error)?;
+        Ok(Self::from_writer(BufWriter::new(file), format))
+    }
+
+    /// Keeps what `path` holds, so a resumed run can cut it back to its checkpoint and
+    /// carry on. Created with its header if missing.
+    pub fn open(path: impl AsRef<Path>, format: ReportFormat) -> Result<Self, RuntimeError> {
+        Ok(Self {
+            writer: RecordWriter::open(path, format, REPORT_HEADER)?,
+        })
+    }
+
+    pub fn from_writer(writer: impl Write + Send + Sync + 'static, format: ReportFormat) -> Self {
+        Self {
+            writer: RecordWriter::new(writer, format, REPORT_HEADER),
+        }
+    }
+
+    pub(crate) fn write(&mut self, rejections: &[Rejection]) -> Result<(), RuntimeError> {
+        self.writer.write(rejections)
+    }
+
+    pub(crate) fn offset(&self) -> Result<Option<u64>, RuntimeError> {
+        self.writer.offset()
+    }
+
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<(), RuntimeError> {
+        self.writer.truncate(offset)
+    }
+}
+
+/// Csv rows or JSON lines, written as they come. A csv gets its header up front so an
+/// empty one is still valid.
+pub(crate) struct RecordWriter {
+    writer: Records,
+    header: &'static [&'static str],
+    file: Option<File>, // the file written to, if any, for `offset` and `truncate`
+}
+
+enum Records {
+    Csv(Box<csv::Writer<Box<dyn Write + Send + Sync>>>),
+    JsonLines(Box<dyn Write + Send + Sync>),
+}
+
+impl RecordWriter {
+    pub(crate) fn new(
+        writer: impl Write + Send + Sync + 'static,
+        format: ReportFormat,
+        header: &'static [&'static str],
+    ) -> Self {
+        let mut writer = Self::unstarted(writer, None, format, header);
+        let _ = writer.write_header();
+        writer
+    }
+
+    /// Appends to `path`. The header is only written if the file is empty.
+    pub(crate) fn open(
+        path: impl AsRef<Path>,
+        format: ReportFormat,
+        header: &'static [&'static str],
+    ) -> Result<Self, RuntimeError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(report_error)?;
+        let len = file.metadata().map_err(report_error)?.len();
+        let handle = file.try_clone().map_err(report_error)?;
+        let mut writer = Self::unstarted(BufWriter::new(file), Some(handle), format, header);
+        if len == 0 {
+            writer.write_header()?;
+        }
+        Ok(writer)
+    }
+
+    fn unstarted(
+        writer: impl Write + Send + Sync + 'static,
+        file: Option<File>,
+        format: ReportFormat,
+        header: &'static [&'static str],
+    ) -> Self {
+        let writer: Box<dyn Write + Send + Sync> = Box::new(writer);
+        let writer = match format {
+            ReportFormat::Csv => Records::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(writer),
+            )),
+            ReportFormat::JsonLines => Records::JsonLines(writer),
+        };
+        Self {
+            writer,
+            header,
+            file,
+        }
+    }
+
+    fn write_header(&mut self) -> Result<(), RuntimeError> {
+        if let Records::Csv(writer) = &mut self.writer {
+            writer.write_record(self.header).map_err(report_error)?;
+            writer.flush().map_err(report_error)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write<T: Serialize>(&mut self, records: &[T]) -> Result<(), RuntimeError> {
+        match &mut self.writer {
+            Records::Csv(writer) => {
+                for record in records {
+                    writer.serialize(record).map_err(report_error)?;
+                }
+                writer.flush().map_err(report_error)
+            }
+            Records::JsonLines(writer) => {
+                for record in records {
+                    serde_json::to_writer(&mut *writer, record).map_err(report_error)?;
+                    writer.write_all(b"\n").map_err(report_error)?;
+                }
+                writer.flush().map_err(report_error)
+            }
+        }
+    }
+
+    /// Bytes in the file so far, `None` unless writing to a file
+    pub(crate) fn offset(&self) -> Result<Option<u64>, RuntimeError> {
+        match &self.file {
+            Some(file) => Ok(Some(file.metadata().map_err(report_error)?.len())),
+            None => Ok(None),
+        }
+    }
+
+    /// Drops everything written after `offset`, the header comes back when that is all of
+    /// it. Anything but a file is left as is.
+    pub(crate) fn truncate(&mut self, offset: u64) -> Result<(), RuntimeError> {
+        let file = match &self.file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        file.set_len(offset).map_err(report_error)?;
+        if offset == 0 {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+}
+
+fn report_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string()))
+}
@@ -1,190 +1,1073 @@
+use crate::checkpoint::{Checkpoint, CHECKPOINT_FILE_NAME};
 use crate::client_account::ClientAccount;
-use crate::constants::{BATCH_SIZE, TEMP_DIRECTORY_LOC};
-use crate::error::RuntimeError::{NonRecoverable, Recoverable};
+use crate::client_actor::{ClientActor, ClientEvent};
+use crate::config::EngineConfig;
+use crate::constants::BATCH_SIZE;
+use crate::engine::{self, Rules};
+use crate::error::RuntimeError::NonRecoverable;
 use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::fee::FeeSchedule;
+use crate::history::HistoryWriter;
+use crate::input::{BatchReader, Input};
+use crate::ledger::{JournalWriter, Ledger};
+use crate::limits::WithdrawalLimits;
+use crate::output::{self, OutputFormat};
+use crate::policy::{DisputePolicy, ErrorPolicy, ExpiredDisputePolicy, FeePolicy};
+use crate::report::{Rejection, RejectionReport, SourceRecord};
+use crate::scratch::ScratchDirectory;
+use crate::store::{
+    FileTransactionStore, LogTransactionStore, MemoryTransactionStore, SerializableState,
+    SerializableTransactionType, StoreBackend, TransactionStore,
+};
 use crate::transaction::{CSVTransaction, CSVTransactionType};
-use itertools::Itertools;
-use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
-use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use csv::StringRecord;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError};
+use strum::IntoEnumIterator;
+use tokio::sync::mpsc;
 
 pub struct Runner {
-    file: PathBuf,
-    client_map: HashMap<u16, Arc<Mutex<ClientAccount>>>, // threaded interior mutability
+    input: std::sync::Mutex<Input>, // only used through `get_mut`, so readers needn't be Sync
+    accounts: HashMap<u16, ClientAccount>, // owned by the client actors while a run is going,
+    // copied from them after every `append`
+    new_peers: HashSet<u16>, // spawned with a new account only to lend it, see `keeps`
+    touched: HashSet<u16>,   // clients the current call spawned or sent anything to
+    clients: Option<Clients>, // kept between `append` calls
+    store: Arc<dyn TransactionStore>,
+    checkpoint: Option<PathBuf>,
+    scratch: Option<ScratchDirectory>,
+    rejection_report: Option<RejectionReport>,
+    journal: Option<JournalWriter>,
+    ledger: Ledger,
+    history: Option<HistoryWriter>,
+    rejected: Option<Vec<Rejection>>, // rejections of the current `append`
+    halted: Option<String>,           // the failure that halted a client, see `append`
+    appended_lines: u64,              // the rows of the next `append` are numbered after these
+    error_policy: ErrorPolicy,
+    rules: Rules,
+    batch_size: usize,
+    clock: Option<u64>, // latest timestamp read
 }
 
 impl Runner {
-    pub fn new(file: PathBuf) -> Self {
+    /// Runner backed by the store `config.store` picks. The file and log stores live in
+    /// a scratch directory of the runner's own under `config.scratch_root`, checkpointing
+    /// next to them. The in memory store doesn't checkpoint.
+    pub fn new(input: impl Into<Input>, config: &EngineConfig) -> Result<Self, RuntimeError> {
+        config.validate()?;
+        let runner = match config.store {
+            StoreBackend::Memory => {
+                Self::with_store(input, Arc::new(MemoryTransactionStore::new()))
+            }
+            _ => Self::with_scratch(
+                input,
+                ScratchDirectory::create(&config.scratch_root)?,
+                config,
+            )?,
+        };
+        Ok(runner.with_config(config))
+    }
+
+    /// Runner picking up the scratch directory a failed run left behind, so `resume` can
+    /// continue it. `config.store` has to be the store that run used.
+    pub fn open(
+        input: impl Into<Input>,
+        directory: impl Into<PathBuf>,
+        config: &EngineConfig,
+    ) -> Result<Self, RuntimeError> {
+        config.validate()?;
+        if config.store == StoreBackend::Memory {
+            return Err(NonRecoverable(RuntimeErrorType::CheckpointError(
+                "The in memory store can't be resumed".to_string(),
+            )));
+        }
+        let runner = Self::with_scratch(input, ScratchDirectory::open(directory)?, config)?;
+        Ok(runner.with_config(config))
+    }
+
+    /// Runner that owns `scratch`, checkpoints into it and keeps its store there as well,
+    /// so everything goes away with the directory
+    fn with_scratch(
+        input: impl Into<Input>,
+        mut scratch: ScratchDirectory,
+        config: &EngineConfig,
+    ) -> Result<Self, RuntimeError> {
+        if config.retain_scratch {
+            scratch.retain();
+        }
+        let store: Arc<dyn TransactionStore> = match config.store {
+            StoreBackend::Log => Arc::new(LogTransactionStore::open(scratch.path())?),
+            _ => Arc::new(FileTransactionStore::new(scratch.path())),
+        };
+        let checkpoint = scratch.path().join(CHECKPOINT_FILE_NAME);
+        let mut runner = Self::with_store(input, store).with_checkpoint(checkpoint);
+        runner.scratch = Some(scratch);
+        Ok(runner)
+    }
+
+    pub(crate) fn with_store(input: impl Into<Input>, store: Arc<dyn TransactionStore>) -> Self {
         Self {
-            file,
-            client_map: HashMap::new(),
+            input: std::sync::Mutex::new(input.into()),
+            accounts: HashMap::new(),
+            new_peers: HashSet::new(),
+            touched: HashSet::new(),
+            clients: None,
+            store,
+            checkpoint: None,
+            scratch: None,
+            rejection_report: None,
+            journal: None,
+            ledger: Ledger::default(),
+            history: None,
+            rejected: None,
+            halted: None,
+            appended_lines: 0,
+            error_policy: ErrorPolicy::default(),
+            rules: Rules::default(),
+            batch_size: BATCH_SIZE,
+            clock: None,
         }
     }
 
+    /// Takes the batch size, both policies, the dispute settings, the fees and the
+    /// withdrawal limits of `config`
+    pub fn with_config(self, config: &EngineConfig) -> Self {
+        let mut runner = self
+            .with_batch_size(config.batch_size)
+            .with_error_policy(config.error_policy)
+            .with_dispute_policy(config.dispute_policy)
+            .with_max_dispute_cycles(config.max_dispute_cycles)
+            .with_frozen_account_disputes(config.frozen_account_disputes)
+            .with_fees(config.fees.clone())
+            .with_fee_policy(config.fee_policy)
+            .with_withdrawal_limits(config.limits);
+        runner.rules.disputes.window = config.dispute_window();
+        runner.rules.disputes.deadline = config.dispute_deadline();
+        runner.rules.disputes.expired = config.expired_disputes;
+        runner
+    }
+
+    /// Rows read at once, and between two checkpoints
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Writes a checkpoint to `path` after every batch so `resume` can continue from it
+    pub(crate) fn with_checkpoint(mut self, path: PathBuf) -> Self {
+        self.checkpoint = Some(path);
+        self
+    }
+
+    /// Lists every row that was not applied, with its line and the reason, in `report`.
+    /// A resume cuts a report from `RejectionReport::open` back to the checkpoint.
+    pub fn with_rejection_report(mut self, report: RejectionReport) -> Self {
+        self.rejection_report = Some(report);
+        self
+    }
+
+    /// Writes the journal entry of every applied transaction to `journal`.
+    /// A resume cuts a journal from `JournalWriter::open` back to the checkpoint.
+    pub fn with_journal(mut self, journal: JournalWriter) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Writes an `AccountEvent` for every row to `history`, which `History::read` loads
+    /// back to tell the balance of a client as of any line or transaction. Checkpoints
+    /// only keep how far it got.
+    pub fn with_history(mut self, history: HistoryWriter) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.rules.disputes.policy = dispute_policy;
+        self
+    }
+
+    /// How often one transaction can be disputed, resolved disputes included
+    pub fn with_max_dispute_cycles(mut self, max_cycles: u32) -> Self {
+        self.rules.disputes.max_cycles = max_cycles;
+        self
+    }
+
+    /// Lets disputes, resolves and chargebacks through on frozen accounts
+    pub fn with_frozen_account_disputes(mut self, allowed: bool) -> Self {
+        self.rules.disputes.frozen_accounts = allowed;
+        self
+    }
+
+    /// Fees charged on deposits and withdrawals, none by default
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.rules.fees = Arc::new(fees);
+        self
+    }
+
+    pub fn with_fee_policy(mut self, fee_policy: FeePolicy) -> Self {
+        self.rules.disputes.fee_policy = fee_policy;
+        self
+    }
+
+    /// Rules withdrawals are checked against, none by default
+    pub fn with_withdrawal_limits(mut self, limits: WithdrawalLimits) -> Self {
+        self.rules.limits = limits;
+        self
+    }
+
+    /// Seconds after a transaction it can still be disputed, if both have a timestamp
+    pub fn with_dispute_window(mut self, window: u64) -> Self {
+        self.rules.disputes.window = Some(window);
+        self
+    }
+
+    /// Seconds a dispute can stay open before `expired` settles it. Expired disputes are
+    /// settled before the row whose timestamp moves the clock past their deadline.
+    pub fn with_dispute_deadline(mut self, deadline: u64, expired: ExpiredDisputePolicy) -> Self {
+        self.rules.disputes.deadline = Some(deadline);
+        self.rules.disputes.expired = expired;
+        self
+    }
+
+    /// Processes the whole input, discarding anything left by an earlier run
     pub async fn run(&mut self) -> Result<(), RuntimeError> {
-        let _ = tokio::fs::remove_dir_all(PathBuf::from(TEMP_DIRECTORY_LOC)).await; // do if possible
-        tokio::fs::create_dir_all(PathBuf::from(TEMP_DIRECTORY_LOC))
-            .await
-            .map_err(|e| NonRecoverable(RuntimeErrorType::TransactionFileOps(e.to_string())))?;
-
-        let mut result = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .from_path(self.file.clone())
-            .map_err(|e| NonRecoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string())))?;
-
-        for res in &result.records().chunks(BATCH_SIZE) {
-            // process BATCH_SIZE records at once
-            let transactions: Vec<CSVTransaction> = res
-                .map(|line_result| match line_result {
-                    Ok(line) => {
-                        let csv_transaction_result = CSVTransaction::try_from(line);
-                        match csv_transaction_result {
-                            Ok(csv_transaction) => Some(csv_transaction),
-                            Err(_e) => None,
-                        }
+        if let Some(path) = &self.checkpoint {
+            Checkpoint::remove(path).await?;
+        }
+        self.store.clear().await?;
+        self.clients = None;
+        self.accounts.clear();
+        self.new_peers.clear();
+        self.clock = None;
+        self.ledger = Ledger::default();
+        self.halted = None;
+        self.appended_lines = 0;
+        if let Some(history) = &mut self.history {
+            history.truncate(0)?;
+        }
+        if let Some(report) = &mut self.rejection_report {
+            report.truncate(0)?;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.truncate(0)?;
+        }
+        let result = self.process(None).await;
+        let result = self.finish(result).await;
+        self.retain_scratch_on_error(result)
+    }
+
+    /// Continues from the last checkpoint. Store writes made after it are rolled back and
+    /// the interrupted batch is applied again. Starts over if no checkpoint was written.
+    pub async fn resume(&mut self) -> Result<(), RuntimeError> {
+        let path = self.checkpoint.clone().ok_or_else(|| {
+            NonRecoverable(RuntimeErrorType::CheckpointError(
+                "Checkpoints are not enabled for this runner".to_string(),
+            ))
+        })?;
+        let checkpoint = match Checkpoint::read(&path).await? {
+            None => return self.run().await,
+            Some(x) => x,
+        };
+        self.store.rollback(checkpoint.store_marker).await?;
+        self.clients = None;
+        self.new_peers.clear();
+        let position = checkpoint.position();
+        self.accounts = checkpoint
+            .accounts
+            .into_iter()
+            .map(|account| (account.id, account))
+            .collect();
+        self.clock = checkpoint.clock;
+        self.ledger = checkpoint.ledger;
+        self.halted = None;
+        self.appended_lines = 0;
+        if let Some(history) = &mut self.history {
+            history.truncate(checkpoint.history_offset.unwrap_or_default())?;
+        }
+        if let Some(report) = &mut self.rejection_report {
+            report.truncate(checkpoint.rejections_offset.unwrap_or_default())?;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.truncate(checkpoint.journal_offset.unwrap_or_default())?;
+        }
+        let result = self.process(Some(position)).await;
+        let result = self.finish(result).await;
+        self.retain_scratch_on_error(result)
+    }
+
+    /// Applies `input` on top of the accounts and transactions left by earlier calls,
+    /// which a run would discard, and returns the rows it rejected. Nothing is cleared
+    /// afterwards, so later input can still dispute transactions read here. The returned
+    /// rejections count lines from the start of `input`, while the rejection report and
+    /// history number them on from the earlier calls, so every row keeps a line of its
+    /// own there. Once a client halted under `ErrorPolicy::Halt` every
+    /// later call is refused.
+    ///
+    /// Client actors outlive the call, and only the clients `input` touches are waited
+    /// on. No checkpoint is written and the ledger isn't verified.
+    pub async fn append(
+        &mut self,
+        input: impl Into<Input>,
+    ) -> Result<Vec<Rejection>, RuntimeError> {
+        if let Some(failure) = &self.halted {
+            return Err(NonRecoverable(RuntimeErrorType::Halted(failure.clone())));
+        }
+        self.input = std::sync::Mutex::new(input.into());
+        self.rejected = Some(vec![]);
+        let lines_before = self.appended_lines;
+        let mut clients = self.clients.take().unwrap_or_else(Clients::new);
+        let result = self.feed(&mut clients).await;
+        self.clients = Some(clients);
+        let mut rejected = self.rejected.take().unwrap_or_default();
+        for rejection in &mut rejected {
+            rejection.line -= lines_before;
+        }
+        result.map(|_| rejected)
+    }
+
+    fn input(&mut self) -> &mut Input {
+        self.input.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Where the store and checkpoints of this run live, if the runner owns the directory
+    pub fn scratch_directory(&self) -> Option<&Path> {
+        self.scratch.as_ref().map(ScratchDirectory::path)
+    }
+
+    /// A failed run keeps its scratch directory so it can be inspected or resumed
+    fn retain_scratch_on_error(
+        &mut self,
+        result: Result<(), RuntimeError>,
+    ) -> Result<(), RuntimeError> {
+        if result.is_err() {
+            if let Some(scratch) = &mut self.scratch {
+                scratch.retain();
+            }
+        }
+        result
+    }
+
+    /// Logs the outcomes and clears the store and checkpoint of a run that went through
+    async fn finish(&mut self, result: Result<(), RuntimeError>) -> Result<(), RuntimeError> {
+        result?;
+        if log::log_enabled!(log::Level::Info) {
+            self.log_outcomes().await?;
+        }
+        let _ = self.store.clear().await; // do if possible
+        if let Some(path) = &self.checkpoint {
+            Checkpoint::remove(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn process(&mut self, start: Option<csv::Position>) -> Result<(), RuntimeError> {
+        log::info!(
+            "Processing with dispute policy {}, at most {} disputes per transaction, disputes on frozen accounts {}, fee policy {}, error policy {}",
+            self.rules.disputes.policy,
+            self.rules.disputes.max_cycles,
+            self.rules.disputes.frozen_accounts,
+            self.rules.disputes.fee_policy,
+            self.error_policy
+        );
+        let mut reader = self.input().open(start)?;
+        let (events, mut event_receiver) = mpsc::unbounded_channel();
+        // actors are spawned for the clients the input touches, the others stay put
+        let mut actors: HashMap<u16, ClientActor> = HashMap::new();
+
+        let dispatched = self
+            .dispatch(&mut reader, &mut actors, &events, &mut event_receiver, true)
+            .await;
+        // every actor hands its account back, whether or not the run failed
+        for (id, actor) in actors {
+            let account = actor.stop().await?;
+            if self.keeps(&account) {
+                self.accounts.insert(id, account);
+            }
+        }
+        self.new_peers.clear();
+        dispatched?;
+        self.handle_events(&mut event_receiver)?;
+        self.ledger.verify(self.accounts.values())
+    }
+
+    /// The input of an `append`, applied by the actors earlier calls left running. Every
+    /// client it touched catches up before its account is copied back and the events are
+    /// handled, the others are left alone.
+    async fn feed(&mut self, clients: &mut Clients) -> Result<(), RuntimeError> {
+        let mut reader = self.input().open(None)?.after_line(self.appended_lines);
+        self.touched.clear();
+        let dispatched = self
+            .dispatch(
+                &mut reader,
+                &mut clients.actors,
+                &clients.events,
+                &mut clients.event_receiver,
+                false,
+            )
+            .await;
+        self.appended_lines = reader.last_line();
+        for id in std::mem::take(&mut self.touched) {
+            let account = clients.actors[&id].snapshot().await?;
+            if self.keeps(&account) {
+                self.accounts.insert(id, account);
+            }
+        }
+        dispatched?;
+        self.handle_events(&mut clients.event_receiver)?;
+        // nothing is rolled back to without checkpoints, so the undo journal can go
+        self.store.commit().await.map(|_| ())
+    }
+
+    /// Hands rows to the actor of their client as they are read, never waiting on a client
+    /// unless its mailbox is full or a checkpoint is due. Without `checkpoints` the
+    /// events are only handled after every batch.
+    async fn dispatch(
+        &mut self,
+        reader: &mut BatchReader,
+        actors: &mut HashMap<u16, ClientActor>,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        event_receiver: &mut mpsc::UnboundedReceiver<ClientEvent>,
+        checkpoints: bool,
+    ) -> Result<(), RuntimeError> {
+        loop {
+            // read batch_size records at once
+            let res = reader.next_batch(self.batch_size).await?;
+            if res.is_empty() {
+                return Ok(());
+            }
+            for (line, line_result) in res {
+                let (source, transaction) = match Self::parse(line, line_result) {
+                    Ok(x) => x,
+                    Err(rejection) => {
+                        let _ = events.send(ClientEvent::Rejected(rejection));
+                        continue;
                     }
-                    Err(e) => {
-                        log::error!("Result invalid {:?}", e);
-                        None
+                };
+                // disputes the row's timestamp leaves past their deadline are settled first
+                if let Some(timestamp) = transaction.timestamp {
+                    if self.clock.is_none_or(|x| x < timestamp) {
+                        self.clock = Some(timestamp);
+                        self.settle_expired_disputes(actors, events, line).await?;
                     }
-                })
-                .filter(|x| x.is_some())
-                .map(|e| e.expect("Filtered out none values"))
-                .collect();
-
-            let csv_transactions_grouped_by_client = {
-                // doing this as group by apparently is unstable
-                let mut grouped_transactions: HashMap<u16, Vec<CSVTransaction>> = HashMap::new();
-                for t in transactions {
-                    grouped_transactions
-                        .entry(t.client_id)
-                        .or_insert_with(Vec::new);
-                    grouped_transactions
-                        .get_mut(&t.client_id)
-                        .expect("above line makes sure we have a value")
-                        .push(t);
                 }
-                grouped_transactions
+                self.route(actors, events, source, transaction).await?;
+            }
+            if checkpoints {
+                self.write_checkpoint(actors, event_receiver, &reader.position())
+                    .await?;
+            } else {
+                self.handle_events(event_receiver)?;
+            }
+        }
+    }
+
+    /// Sends a row to the actor of its client, along with the account of the peer it touches
+    async fn route(
+        &mut self,
+        actors: &mut HashMap<u16, ClientActor>,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        source: SourceRecord,
+        transaction: CSVTransaction,
+    ) -> Result<(), RuntimeError> {
+        let client_id = transaction.client_id;
+        self.new_peers.remove(&client_id);
+        match self.peer_of(actors, events, &transaction).await? {
+            Some(peer_id) if peer_id != client_id => {
+                if !actors.contains_key(&peer_id) && !self.accounts.contains_key(&peer_id) {
+                    self.new_peers.insert(peer_id);
+                }
+                // the peer waits until its account comes back, nothing else does
+                let (peer, returned) = self.actor(actors, peer_id, events).lend().await?;
+                self.actor(actors, client_id, events)
+                    .send_with_peer(source, transaction, peer, returned)
+                    .await;
+            }
+            _ => {
+                self.actor(actors, client_id, events)
+                    .send(source, transaction)
+                    .await
+            }
+        }
+        Ok(())
+    }
+
+    /// Settles every dispute that was open longer than the deadline at the clock. Every
+    /// actor catches up first, so the store holds every dispute read so far. Settlements
+    /// go through the actors like rows do, with `line` the row that moved the clock.
+    async fn settle_expired_disputes(
+        &mut self,
+        actors: &mut HashMap<u16, ClientActor>,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        line: u64,
+    ) -> Result<(), RuntimeError> {
+        let (deadline, now) = match (self.rules.disputes.deadline, self.clock) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Ok(()),
+        };
+        futures::future::try_join_all(actors.values().map(ClientActor::snapshot)).await?;
+        let expired = self
+            .store
+            .expired_disputes(now.saturating_sub(deadline))
+            .await?;
+        for transaction in expired {
+            let settlement = engine::settlement(&transaction, self.rules.disputes.expired, now);
+            let source = SourceRecord {
+                line,
+                raw: format!(
+                    "{},{},{},,,,{}",
+                    settlement.transaction_type, settlement.client_id, settlement.tx_id, now
+                ),
             };
+            self.route(actors, events, source, settlement).await?;
+        }
+        Ok(())
+    }
 
-            let mut handles = vec![];
-            for (key, client_transactions) in csv_transactions_grouped_by_client.into_iter() {
-                let account = {
-                    self.client_map
-                        .entry(key)
-                        .or_insert_with(|| Arc::new(Mutex::new(ClientAccount::new_account(key))));
-                    self.client_map.get(&key)
+    fn parse(
+        line: u64,
+        line_result: csv::Result<StringRecord>,
+    ) -> Result<(SourceRecord, CSVTransaction), Rejection> {
+        match line_result {
+            Ok(record) => {
+                let source = SourceRecord::new(line, &record);
+                match CSVTransaction::try_from(record) {
+                    Ok(transaction) => Ok((source, transaction)),
+                    Err(e) => Err(Rejection::new(&source, &e.into_type())),
                 }
-                .expect("Will be present since is defaulted if not present")
-                .clone();
-
-                let handle = tokio::spawn(async move {
-                    for transaction in client_transactions {
-                        match transaction.transaction_type {
-                            CSVTransactionType::Deposit => {
-                                //takes a state 1 transaction and writes it
-                                if let Err(NonRecoverable(e_type)) = account
-                                    .lock()
-                                    .await
-                                    .execute_deposit(transaction.try_into()?)
-                                    .await
-                                {
-                                    panic!("{:?}", e_type);
-                                }
-                            }
-                            CSVTransactionType::Withdrawal => {
-                                //takes a state 1 transaction and writes it
-                                if let Err(NonRecoverable(e_type)) = account
-                                    .lock()
-                                    .await
-                                    .execute_withdrawal(transaction.try_into()?)
-                                    .await
-                                {
-                                    panic!("{:?}", e_type);
-                                }
-                            }
-                            CSVTransactionType::Dispute => {
-                                //Finds a state1 transaction in file
-                                // Converts it into state 2
-                                if let Err(NonRecoverable(e_type)) = account
-                                    .lock()
-                                    .await
-                                    .execute_dispute(transaction.try_into()?)
-                                    .await
-                                {
-                                    panic!("{:?}", e_type);
-                                }
-                            }
-                            CSVTransactionType::Resolve => {
-                                //Finds a state2 transaction in file
-                                //Writes it back to state 3
-                                if let Err(NonRecoverable(e_type)) = account
-                                    .lock()
-                                    .await
-                                    .execute_resolve(transaction.try_into()?)
-                                    .await
-                                {
-                                    panic!("{:?}", e_type);
-                                }
-                            }
-                            CSVTransactionType::Chargeback => {
-                                //Finds a state2 transaction in file
-                                //Writes it back to state 3
-                                if let Err(NonRecoverable(e_type)) = account
-                                    .lock()
-                                    .await
-                                    .execute_chargeback(transaction.try_into()?)
-                                    .await
-                                {
-                                    panic!("{:?}", e_type);
-                                }
-                            }
-                        }
+            }
+            Err(e) => {
+                log::error!("Result invalid {:?}", e);
+                let source = SourceRecord {
+                    line,
+                    raw: String::new(),
+                };
+                Err(Rejection::new(
+                    &source,
+                    &RuntimeErrorType::CSVLineParseError(e.to_string()),
+                ))
+            }
+        }
+    }
+
+    /// The second client a row touches: the destination of a transfer, or the client a
+    /// charged back transfer came from, as the store has it
+    async fn peer_of(
+        &mut self,
+        actors: &mut HashMap<u16, ClientActor>,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        transaction: &CSVTransaction,
+    ) -> Result<Option<u16>, RuntimeError> {
+        match transaction.transaction_type {
+            CSVTransactionType::Transfer => Ok(transaction.destination),
+            CSVTransactionType::Chargeback => {
+                let stored = match self.store.get(transaction.tx_id).await? {
+                    Some(x) => Some(x),
+                    None => {
+                        // a transfer to the client may still be applied by the sender's actor,
+                        // which holds the client's account until it is stored
+                        self.actor(actors, transaction.client_id, events)
+                            .snapshot()
+                            .await?;
+                        self.store.get(transaction.tx_id).await?
                     }
-                    Result::Ok::<(), RuntimeError>(())
-                });
-                handles.push(handle);
+                };
+                Ok(stored
+                    .filter(|x| x.transaction_type == SerializableTransactionType::Transfer)
+                    .map(|x| x.client_id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether `account` stays once its actor stopped. A client only seen as the
+    /// destination of rejected transfers never had an account.
+    fn keeps(&self, account: &ClientAccount) -> bool {
+        !self.new_peers.contains(&account.id) || !account.is_untouched()
+    }
+
+    /// Actor of `client_id`, spawned with its account, or a new one, on first use
+    fn actor<'a>(
+        &mut self,
+        actors: &'a mut HashMap<u16, ClientActor>,
+        client_id: u16,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+    ) -> &'a ClientActor {
+        self.touched.insert(client_id);
+        actors.entry(client_id).or_insert_with(|| {
+            let account = self
+                .accounts
+                .remove(&client_id)
+                .unwrap_or_else(|| ClientAccount::new_account(client_id));
+            self.spawn_actor(account, events)
+        })
+    }
+
+    fn spawn_actor(
+        &self,
+        account: ClientAccount,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+    ) -> ClientActor {
+        ClientActor::spawn(
+            account,
+            self.store.clone(),
+            self.error_policy,
+            self.rules.clone(),
+            self.history.is_some(),
+            events.clone(),
+        )
+    }
+
+    /// Writes the rejections and journal entries reported so far, posts the entries to
+    /// the ledger and writes the history. The row that halted a client is reported as
+    /// rejected along with the others, then its failure is returned.
+    fn handle_events(
+        &mut self,
+        event_receiver: &mut mpsc::UnboundedReceiver<ClientEvent>,
+    ) -> Result<(), RuntimeError> {
+        let mut rejections = vec![];
+        let mut journal = vec![];
+        let mut recorded = vec![];
+        let mut failure = None;
+        while let Ok(event) = event_receiver.try_recv() {
+            match event {
+                ClientEvent::Rejected(rejection) => rejections.push(rejection),
+                ClientEvent::Posted(entries) => journal.extend(entries),
+                ClientEvent::Recorded(events) => recorded.extend(events),
+                ClientEvent::Halted(rejection, e) => {
+                    rejections.push(rejection);
+                    failure = failure.or(Some(e));
+                }
             }
-            //await before starting the next batch
-            for x in futures::future::join_all(handles).await {
-                let _ =
-                    x.map_err(|e| RuntimeError::NonRecoverable(RuntimeErrorType::JoinError(e)))?;
+        }
+        for entry in &journal {
+            self.ledger.post(entry)?;
+        }
+        if let Some(writer) = &mut self.journal {
+            writer.write(&journal)?;
+        }
+        if let Some(history) = &mut self.history {
+            history.write(&recorded)?;
+        }
+        rejections.sort_by_key(|r| r.line);
+        if let Some(report) = &mut self.rejection_report {
+            report.write(&rejections)?;
+        }
+        if let Some(rejected) = &mut self.rejected {
+            rejected.extend(rejections);
+        }
+        match failure {
+            Some(e) => {
+                self.halted = Some(e.to_string());
+                Err(e)
             }
+            None => Ok(()),
         }
+    }
 
-        let _ = tokio::fs::remove_dir_all(PathBuf::from(TEMP_DIRECTORY_LOC))
-            .await
-            .map_err(|e| Recoverable(RuntimeErrorType::CSVFileReadWriteError(e.to_string())));
+    /// Logs how many transactions ended in each state, before the store is cleared
+    async fn log_outcomes(&self) -> Result<(), RuntimeError> {
+        let mut outcomes: HashMap<SerializableState, usize> = HashMap::new();
+        for transaction in self.store.transactions().await? {
+            *outcomes.entry(transaction.state()).or_default() += 1;
+        }
+        for state in SerializableState::iter() {
+            log::info!(
+                "{} transactions {}",
+                outcomes.get(&state).unwrap_or(&0),
+                state
+            );
+        }
         Ok(())
     }
 
-    pub async fn print_all_accounts(&self) {
-        println!("client, available, held, total, locked");
-        for (client, value) in &self.client_map {
-            let guard = value.lock().await;
-            println!(
-                "{},{},{},{},{}",
-                client,
-                &guard.available,
-                &guard.held,
-                (&guard.available + &guard.held),
-                guard.locked
-            );
+    /// Without checkpoints this only reports rejections. With them every actor first
+    /// catches up with the reader, so accounts, store and rejections all match `position`.
+    async fn write_checkpoint(
+        &mut self,
+        actors: &HashMap<u16, ClientActor>,
+        event_receiver: &mut mpsc::UnboundedReceiver<ClientEvent>,
+        position: &csv::Position,
+    ) -> Result<(), RuntimeError> {
+        let path = match &self.checkpoint {
+            None => return self.handle_events(event_receiver),
+            Some(x) => x.clone(),
+        };
+        let mut accounts =
+            futures::future::try_join_all(actors.values().map(ClientActor::snapshot)).await?;
+        accounts.retain(|x| self.keeps(x));
+        accounts.extend(self.accounts.values().cloned()); // clients the batch did not touch
+        self.handle_events(event_receiver)?;
+        let store_marker = self.store.commit().await?;
+        Checkpoint {
+            input_byte: position.byte(),
+            input_line: position.line(),
+            input_record: position.record(),
+            store_marker,
+            accounts,
+            clock: self.clock,
+            ledger: self.ledger.clone(),
+            history_offset: self.history.as_ref().map(HistoryWriter::offset),
+            rejections_offset: match &self.rejection_report {
+                Some(x) => x.offset()?,
+                None => None,
+            },
+            journal_offset: match &self.journal {
+                Some(x) => x.offset()?,
+                None => None,
+            },
         }
+        .write(&path)
+        .await
     }
 
-    #[allow(dead_code)]
-    /// Intended for tests only. Helps verify with automated tests.
+    /// Writes every account to `writer`, sorted by client id, amounts with `scale` decimals
+    pub async fn write_accounts(
+        &self,
+        writer: impl Write,
+        format: OutputFormat,
+        scale: usize,
+    ) -> Result<(), RuntimeError> {
+        output::write_accounts(&mut self.accounts(), writer, format, scale)
+    }
+
+    /// Balance of every ledger account after the last run, checked against the accounts
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Copy of every account as it stands now, in no particular order
+    pub fn accounts(&self) -> Vec<ClientAccount> {
+        self.accounts.values().cloned().collect()
+    }
+
+    /// Copy of the account of `account_id` as it stands now
     pub async fn get_cloned_account_snapshot(&self, account_id: u16) -> Option<ClientAccount> {
-        match &self.client_map.get(&account_id) {
-            None => None,
-            Some(x) => {
-                let guard = x.lock().await;
-                let guard = &*guard;
-                Some(guard.clone())
+        self.accounts.get(&account_id).cloned()
+    }
+}
+
+/// Client actors kept running between `append` calls, with the channel they report on
+struct Clients {
+    actors: HashMap<u16, ClientActor>,
+    events: mpsc::UnboundedSender<ClientEvent>,
+    event_receiver: mpsc::UnboundedReceiver<ClientEvent>,
+}
+
+impl Clients {
+    fn new() -> Self {
+        let (events, event_receiver) = mpsc::unbounded_channel();
+        Self {
+            actors: HashMap::new(),
+            events,
+            event_receiver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::currency::Currency;
+    use crate::history::History;
+    use crate::report::ReportFormat;
+    use crate::store::{SerializableTransaction, Transition, SEGMENT_FILE_NAME};
+    use futures::future::{self, BoxFuture, FutureExt};
+    use std::fs::{self, File};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn amount(x: &str) -> Amount {
+        Amount::from_str(x).expect("valid amount")
+    }
+
+    /// Fails every put once `puts_left` runs out, as if the process died mid batch
+    struct CrashingStore {
+        inner: Arc<dyn TransactionStore>,
+        puts_left: AtomicUsize,
+    }
+
+    impl TransactionStore for CrashingStore {
+        fn get(
+            &self,
+            tx_id: u32,
+        ) -> BoxFuture<'_, Result<Option<SerializableTransaction>, RuntimeError>> {
+            self.inner.get(tx_id)
+        }
+
+        fn put(
+            &self,
+            transaction: SerializableTransaction,
+        ) -> BoxFuture<'_, Result<(), RuntimeError>> {
+            if self.puts_left.fetch_sub(1, Ordering::SeqCst) == 0 {
+                return future::ready(Err(RuntimeError::NonRecoverable(
+                    RuntimeErrorType::TransactionFileOps("Simulated crash".to_string()),
+                )))
+                .boxed();
             }
+            self.inner.put(transaction)
         }
+
+        fn transition(
+            &self,
+            tx_id: u32,
+            transition: Transition,
+            at: Option<u64>,
+        ) -> BoxFuture<'_, Result<(), RuntimeError>> {
+            self.inner.transition(tx_id, transition, at)
+        }
+
+        fn expired_disputes(
+            &self,
+            opened_before: u64,
+        ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+            self.inner.expired_disputes(opened_before)
+        }
+
+        fn transactions(
+            &self,
+        ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+            self.inner.transactions()
+        }
+
+        fn clear(&self) -> BoxFuture<'_, Result<(), RuntimeError>> {
+            self.inner.clear()
+        }
+
+        fn commit(&self) -> BoxFuture<'_, Result<u64, RuntimeError>> {
+            self.inner.commit()
+        }
+
+        fn rollback(&self, marker: u64) -> BoxFuture<'_, Result<(), RuntimeError>> {
+            self.inner.rollback(marker)
+        }
+    }
+
+    async fn crash_and_resume(
+        name: &str,
+        open_store: impl Fn(&Path) -> Result<Arc<dyn TransactionStore>, RuntimeError>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory)?;
+        let input = directory.join("input.csv");
+        let checkpoint = directory.join(CHECKPOINT_FILE_NAME);
+        let store_directory = directory.join("store");
+        let history = directory.join("history.jsonl");
+        let rejections = directory.join("rejections.csv");
+        let journal = directory.join("journal.csv");
+        {
+            let mut file = File::create(&input)?;
+            writeln!(file, "type, client, tx, amount")?;
+            for tx in 1..=10_500 {
+                let client = if tx == 2 { 2 } else { 1 };
+                writeln!(file, "deposit, {}, {}, 1.0", client, tx)?;
+                if tx == 5 || tx == 10_100 {
+                    // rejected before the checkpoint and in the interrupted batch
+                    writeln!(file, "withdrawal, 3, {}, 1.0", 20_000 + tx)?;
+                }
+                if tx == 10_050 {
+                    // lands in the interrupted batch and must be rolled back too
+                    writeln!(file, "dispute, 2, 2,")?;
+                }
+            }
+        }
+
+        let mut runner = Runner::with_store(
+            input.clone(),
+            Arc::new(CrashingStore {
+                inner: open_store(&store_directory)?,
+                puts_left: AtomicUsize::new(10_200),
+            }),
+        )
+        .with_checkpoint(checkpoint.clone())
+        .with_history(HistoryWriter::open(&history)?)
+        .with_rejection_report(RejectionReport::open(&rejections, ReportFormat::Csv)?)
+        .with_journal(JournalWriter::open(&journal, ReportFormat::Csv)?);
+        assert!(runner.run().await.is_err());
+        assert!(checkpoint.exists());
+
+        let mut runner = Runner::with_store(input, open_store(&store_directory)?)
+            .with_checkpoint(checkpoint.clone())
+            .with_history(HistoryWriter::open(&history)?)
+            .with_rejection_report(RejectionReport::open(&rejections, ReportFormat::Csv)?)
+            .with_journal(JournalWriter::open(&journal, ReportFormat::Csv)?);
+        runner.resume().await?;
+        let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+        let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+        assert_eq!(x.available(), amount("10499"));
+        assert_eq!(y.available(), amount("0"));
+        assert_eq!(y.held(), amount("1"));
+        assert!(!checkpoint.exists());
+        // events of the interrupted batch were cut off before it was applied again
+        let history = History::read(std::io::BufReader::new(File::open(&history)?))?;
+        assert_eq!(history.events(1).len(), 10_499);
+        assert_eq!(history.events(2).len(), 2);
+        // so were its rejections, each row is reported once under a single header
+        let report = fs::read_to_string(&rejections)?;
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{}", report);
+        assert!(lines[0].starts_with("line,"));
+        assert!(lines[1].starts_with("7,"));
+        assert!(lines[2].starts_with("10104,"));
+        // and its journal entries, the header is not written again on appending
+        let journal = fs::read_to_string(&journal)?;
+        assert_eq!(journal.matches("tx_id,").count(), 1);
+        assert_eq!(journal.lines().count(), 1 + 10_500 + 1);
+        assert_eq!(journal.matches("\n2,dispute,").count(), 1);
+
+        fs::remove_dir_all(&directory)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn appends_keep_client_actors_and_skip_checkpoints(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut runner = Runner::new(
+            Input::from_reader(std::io::empty()),
+            &EngineConfig::default(),
+        )?;
+        let append = |csv: &str| {
+            Input::from_reader(std::io::Cursor::new(format!(
+                "type, client, tx, amount\n{}",
+                csv
+            )))
+        };
+        runner.append(append("deposit, 1, 1, 5.0\n")).await?;
+        runner
+            .append(append("dispute, 1, 1,\ndeposit, 2, 2, 1.0\n"))
+            .await?;
+
+        let clients = runner.clients.as_ref().expect("kept after an append");
+        assert_eq!(clients.actors.len(), 2);
+        let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+        assert_eq!(x.held(), amount("5"));
+        assert_eq!(runner.accounts().len(), 2);
+        let directory = runner.scratch_directory().expect("file store in scratch");
+        assert!(!directory.join(CHECKPOINT_FILE_NAME).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn appends_number_lines_on_from_earlier_ones() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("appended-{}.jsonl", std::process::id()));
+        let mut runner = Runner::new(
+            Input::from_reader(std::io::empty()),
+            &EngineConfig::default(),
+        )?
+        .with_history(HistoryWriter::open(&path)?);
+        let append = |csv: &str| {
+            Input::from_reader(std::io::Cursor::new(format!(
+                "type, client, tx, amount\n{}",
+                csv
+            )))
+        };
+        runner.append(append("deposit, 1, 1, 5.0\n")).await?;
+        let rejected = runner
+            .append(append("deposit, 1, 2, 3.0\nwithdrawal, 1, 3, 9.0\n"))
+            .await?;
+        // rejections are still told by their line in the input appended
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].line, 3);
+
+        let history = History::read(std::io::BufReader::new(File::open(&path)?))?;
+        let lines = history.events(1).iter().map(|x| x.line).collect::<Vec<_>>();
+        assert_eq!(lines, [2, 4, 5]);
+        let usd = Currency::default();
+        assert_eq!(history.balance_at_line(1, usd, 2).available, amount("5"));
+        assert_eq!(history.balance_at_line(1, usd, 4).available, amount("8"));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn headers_must_follow_the_column_order() -> Result<(), RuntimeError> {
+        let run = |csv: &'static str| async move {
+            let mut runner = Runner::with_store(
+                Input::from_reader(std::io::Cursor::new(csv)),
+                Arc::new(MemoryTransactionStore::new()),
+            );
+            runner.run().await.map(|_| runner)
+        };
+        // the currency would be read as the reason
+        let result = run("type, client, tx, amount, currency\ndeposit, 1, 1, 1.0, EUR\n").await;
+        assert!(matches!(
+            result.map(|_| ()).map_err(RuntimeError::into_type),
+            Err(RuntimeErrorType::CSVLineParseError(_))
+        ));
+
+        let runner =
+            run("type,client,tx,amount,destination,currency\ndeposit,1,1,1.0,,EUR\n").await?;
+        let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+        assert_eq!(x.balance("EUR".parse()?).available, amount("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn runner_is_sync_over_readers_that_are_not() {
+        fn sync<T: Sync>(_: &T) {}
+        let reader: Box<dyn std::io::Read + Send> = Box::new(std::io::empty());
+        let runner = Runner::with_store(
+            Input::from_reader(reader),
+            Arc::new(MemoryTransactionStore::new()),
+        );
+        sync(&runner);
+    }
+
+    #[tokio::test]
+    async fn resume_only_removes_its_own_files() -> Result<(), Box<dyn std::error::Error>> {
+        let root = std::env::temp_dir().join(format!("resume-foreign-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let config = EngineConfig {
+            scratch_root: root.clone(),
+            store: StoreBackend::Log,
+            ..EngineConfig::default()
+        };
+        let input = || Input::from_reader(std::io::Cursor::new("type, client, tx, amount\n"));
+
+        // any directory that isn't a scratch directory is refused and left alone
+        let victim = root.join("victim");
+        fs::create_dir_all(victim.join("nested"))?;
+        fs::write(victim.join("notes.txt"), "keep me")?;
+        assert!(Runner::open(input(), &victim, &config).is_err());
+        assert!(victim.join("notes.txt").exists());
+
+        let mut scratch = ScratchDirectory::create(&root)?;
+        scratch.retain();
+        let directory = scratch.path().to_path_buf();
+        drop(scratch);
+        fs::write(directory.join("notes.txt"), "keep me")?;
+        let mut runner = Runner::open(input(), &directory, &config)?;
+        runner.resume().await?;
+        drop(runner);
+        assert!(directory.join("notes.txt").exists());
+        assert!(!directory.join(SEGMENT_FILE_NAME).exists());
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_after_crash_with_log_store() -> Result<(), Box<dyn std::error::Error>> {
+        crash_and_resume("resume-log-store", |directory| {
+            Ok(Arc::new(LogTransactionStore::open(directory)?))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn resume_after_crash_with_file_store() -> Result<(), Box<dyn std::error::Error>> {
+        crash_and_resume("resume-file-store", |directory| {
+            Ok(Arc::new(FileTransactionStore::new(directory)))
+        })
+        .await
     }
 }
@@ -0,0 +1,99 @@
+use crate::checkpoint::CHECKPOINT_FILE_NAME;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::store::SEGMENT_FILE_NAME;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Written into every directory `create` makes, so `open` only takes scratch directories
+const MARKER_FILE_NAME: &str = "scratch.marker";
+
+/// Working directory owned by a single runner, so several runs can share a root.
+/// Removed on drop unless it is retained.
+#[derive(Debug)]
+pub(crate) struct ScratchDirectory {
+    path: PathBuf,
+    retain: bool,
+    created: bool, // by this process, so nothing but the runner's files are in it
+}
+
+impl ScratchDirectory {
+    /// Creates a directory under `root` with a name no other runner in any process uses
+    pub(crate) fn create(root: impl AsRef<Path>) -> Result<Self, RuntimeError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let name = format!(
+            "run-{}-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            nanos
+        );
+        let path = root.as_ref().join(name);
+        std::fs::create_dir_all(&path).map_err(scratch_error)?;
+        let scratch = Self {
+            path,
+            retain: false,
+            created: true,
+        };
+        std::fs::write(scratch.path.join(MARKER_FILE_NAME), b"").map_err(scratch_error)?;
+        Ok(scratch)
+    }
+
+    /// Picks up a directory `create` made, e.g. to resume the run that left it behind.
+    /// On drop only the files a runner writes are removed, and the directory if that
+    /// leaves it empty.
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self, RuntimeError> {
+        let path = path.into();
+        let ours = [MARKER_FILE_NAME, CHECKPOINT_FILE_NAME]
+            .iter()
+            .any(|x| path.join(x).is_file());
+        if !ours {
+            return Err(RuntimeError::NonRecoverable(
+                RuntimeErrorType::CheckpointError(format!(
+                    "{} is not a scratch directory left by a run",
+                    path.display()
+                )),
+            ));
+        }
+        Ok(Self {
+            path,
+            retain: false,
+            created: false,
+        })
+    }
+
+    /// Keeps the directory and its contents after drop
+    pub(crate) fn retain(&mut self) {
+        self.retain = true;
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDirectory {
+    fn drop(&mut self) {
+        if self.retain {
+            return;
+        }
+        // do if possible
+        if self.created {
+            let _ = std::fs::remove_dir_all(&self.path);
+        } else {
+            // the file store removes its own files when a run finishes
+            for name in [MARKER_FILE_NAME, CHECKPOINT_FILE_NAME, SEGMENT_FILE_NAME] {
+                let _ = std::fs::remove_file(self.path.join(name));
+            }
+            let _ = std::fs::remove_dir(&self.path);
+        }
+    }
+}
+
+fn scratch_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(e.to_string()))
+}
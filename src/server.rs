@@ -0,0 +1,296 @@
+use crate::client_account::ClientAccount;
+use crate::constants::DEFAULT_OUTPUT_SCALE;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::input::Input;
+use crate::output::{self, OutputFormat};
+use crate::report::Rejection;
+use crate::runner::Runner;
+use crate::transaction::CSVTransactionType;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io::Cursor;
+use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Serves a `Runner` over HTTP:
+///
+/// - `POST /transactions` applies a csv body with its header row, or a JSON transaction
+///   or array of them if sent as `application/json`, and answers with the rejected rows
+/// - `GET /accounts` lists every account, `GET /accounts/<client>` one of them
+///
+/// Requests are applied one at a time in the order they arrive and the rows of each in
+/// their order, so every client sees its transactions serially like in a run.
+pub struct HttpServer {
+    runner: Arc<Mutex<Runner>>,
+    output_format: OutputFormat,
+    output_scale: usize,
+}
+
+/// A transaction in a JSON body, with the columns of a csv row
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionRow {
+    #[serde(rename = "type")]
+    transaction_type: CSVTransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<JsonAmount>,
+    reason: Option<String>,
+    destination: Option<u16>,
+    currency: Option<String>,
+    timestamp: Option<u64>,
+}
+
+/// An amount as a string, read exactly like a csv column, or a JSON number, taken as
+/// printed so `1.5` is fine but one with more digits than an f64 keeps may be rounded
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonAmount {
+    Text(String),
+    Number(serde_json::Number),
+}
+
+impl fmt::Display for JsonAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonAmount::Text(x) => x.fmt(f),
+            JsonAmount::Number(x) => x.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TransactionBody {
+    One(TransactionRow),
+    Batch(Vec<TransactionRow>),
+}
+
+#[derive(Serialize)]
+struct Applied {
+    rejected: Vec<Rejection>,
+}
+
+impl HttpServer {
+    /// Accounts are listed as JSON, with the default scale
+    pub fn new(runner: Runner) -> Self {
+        Self {
+            runner: Arc::new(Mutex::new(runner)),
+            output_format: OutputFormat::Json,
+            output_scale: DEFAULT_OUTPUT_SCALE,
+        }
+    }
+
+    pub fn with_output(mut self, format: OutputFormat, scale: usize) -> Self {
+        self.output_format = format;
+        self.output_scale = scale;
+        self
+    }
+
+    /// Runner every request goes through, to share it with other front ends
+    pub fn runner(&self) -> Arc<Mutex<Runner>> {
+        self.runner.clone()
+    }
+
+    /// Serves on `listener` until `shutdown` completes, then lets requests in flight finish
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), RuntimeError> {
+        let server = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let server = server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(request).await) }
+                }))
+            }
+        });
+        hyper::Server::from_tcp(listener)
+            .map_err(server_error)?
+            .serve(make_service)
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(server_error)
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let path: Vec<&str> = request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|x| !x.is_empty())
+            .collect();
+        let result = match (request.method(), path.as_slice()) {
+            (&Method::POST, ["transactions"]) => {
+                let json = request
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|x| x.to_str().ok())
+                    .is_some_and(|x| x.starts_with("application/json"));
+                let body = hyper::body::to_bytes(request.into_body()).await;
+                match body {
+                    Ok(body) => self.apply(body.to_vec(), json).await,
+                    Err(e) => Err((StatusCode::BAD_REQUEST, e.to_string())),
+                }
+            }
+            (&Method::GET, ["accounts"]) => {
+                let accounts = self.runner.lock().await.accounts();
+                self.accounts(accounts)
+            }
+            (&Method::GET, ["accounts", client]) => match client.parse::<u16>() {
+                Ok(client) => {
+                    let account = self
+                        .runner
+                        .lock()
+                        .await
+                        .get_cloned_account_snapshot(client)
+                        .await;
+                    match account {
+                        Some(account) => self.accounts(vec![account]),
+                        None => Err((StatusCode::NOT_FOUND, format!("No account {}", client))),
+                    }
+                }
+                Err(_) => Err((StatusCode::BAD_REQUEST, format!("Bad client {}", client))),
+            },
+            (_, ["transactions"] | ["accounts"] | ["accounts", _]) => Err((
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("{} not allowed", request.method()),
+            )),
+            _ => Err((StatusCode::NOT_FOUND, "No such endpoint".to_string())),
+        };
+        result.unwrap_or_else(|(status, message)| {
+            let mut response = Response::new(Body::from(message));
+            *response.status_mut() = status;
+            response
+        })
+    }
+
+    /// Feeds the body to the runner as csv. Rows of a JSON body are turned into csv
+    /// first, and their rejections carry their position in the body instead of a line.
+    async fn apply(
+        &self,
+        body: Vec<u8>,
+        json: bool,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        let csv = if json {
+            let rows = match serde_json::from_slice(&body) {
+                Ok(TransactionBody::One(x)) => vec![x],
+                Ok(TransactionBody::Batch(x)) => x,
+                Err(e) => return Err((StatusCode::BAD_REQUEST, e.to_string())),
+            };
+            to_csv(&rows).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        } else {
+            body
+        };
+        let input = Input::from_reader(Cursor::new(csv));
+        let mut rejected = self
+            .runner
+            .lock()
+            .await
+            .append(input)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        if json {
+            for rejection in &mut rejected {
+                rejection.line -= 1; // the header row is ours
+            }
+        }
+        let body = serde_json::to_vec(&Applied { rejected })
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        Ok(response("application/json", body))
+    }
+
+    fn accounts(
+        &self,
+        mut accounts: Vec<ClientAccount>,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        let mut body = vec![];
+        output::write_accounts(
+            &mut accounts,
+            &mut body,
+            self.output_format,
+            self.output_scale,
+        )
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let content_type = match self.output_format {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Json => "application/json",
+            OutputFormat::JsonLines => "application/jsonl",
+        };
+        Ok(response(content_type, body))
+    }
+}
+
+fn response(content_type: &'static str, body: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, content_type.parse().expect("valid header"));
+    response
+}
+
+/// Csv rows, header first, in the column order `CSVTransaction` reads them. The fifth
+/// column is the destination of a transfer and the reason of any other row, so a row
+/// with the other one is refused rather than have it dropped.
+fn to_csv(rows: &[TransactionRow]) -> Result<Vec<u8>, RuntimeError> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    let csv_error =
+        |e: csv::Error| RuntimeError::Recoverable(RuntimeErrorType::ParseError(e.to_string()));
+    writer
+        .write_record([
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "reason",
+            "currency",
+            "timestamp",
+        ])
+        .map_err(csv_error)?;
+    for row in rows {
+        let transfer = row.transaction_type == CSVTransactionType::Transfer;
+        let fifth = match (transfer, &row.reason, row.destination) {
+            (true, None, destination) => destination.map(|x| x.to_string()),
+            (false, reason, None) => reason.clone(),
+            (true, Some(_), _) => {
+                return Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                    format!("transfer {} takes a destination, not a reason", row.tx),
+                )))
+            }
+            (false, _, Some(_)) => {
+                return Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                    format!("only a transfer takes a destination, not {}", row.tx),
+                )))
+            }
+        };
+        writer
+            .write_record([
+                row.transaction_type.to_string(),
+                row.client.to_string(),
+                row.tx.to_string(),
+                row.amount
+                    .as_ref()
+                    .map(|x| x.to_string())
+                    .unwrap_or_default(),
+                fifth.unwrap_or_default(),
+                row.currency.clone().unwrap_or_default(),
+                row.timestamp.map(|x| x.to_string()).unwrap_or_default(),
+            ])
+            .map_err(csv_error)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| RuntimeError::Recoverable(RuntimeErrorType::ParseError(e.to_string())))
+}
+
+fn server_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::ServerError(e.to_string()))
+}
@@ -0,0 +1,465 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::store::{
+    not_present_error, DisputeEvent, OpenDisputes, SerializableState, SerializableTransaction,
+    TransactionStore, Transition,
+};
+use futures::future::{BoxFuture, FutureExt};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const JOURNAL_FILE_NAME: &str = "undo.journal";
+
+/// Writes one JSON file per tx_id into `directory`.
+///
+/// Every change is first recorded in an undo journal next to the transaction files so
+/// `rollback` can remove files created, and restore states changed, after a commit.
+/// Open disputes are indexed in memory, `rollback` rebuilds the index from the files.
+pub(crate) struct FileTransactionStore {
+    directory: PathBuf,
+    state_lock: Mutex<()>,    // serializes read-compare-write of state changes
+    journal_lock: Mutex<u64>, // last commit number
+    open_disputes: std::sync::Mutex<OpenDisputes>,
+}
+
+enum JournalEntry {
+    Put(u32),
+    /// State and `disputed_at` of a tx before a transition
+    State(u32, SerializableState, Option<u64>),
+    Commit(u64),
+}
+
+impl FileTransactionStore {
+    pub(crate) fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            state_lock: Mutex::new(()),
+            journal_lock: Mutex::new(0),
+            open_disputes: std::sync::Mutex::new(OpenDisputes::default()),
+        }
+    }
+
+    fn with_open_disputes<T>(&self, f: impl FnOnce(&mut OpenDisputes) -> T) -> T {
+        let mut guard = self
+            .open_disputes
+            .lock()
+            .expect("No code panics while holding this lock");
+        f(&mut guard)
+    }
+
+    fn path(&self, tx_id: u32) -> PathBuf {
+        self.directory.join(tx_id.to_string())
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        self.directory.join(JOURNAL_FILE_NAME)
+    }
+
+    fn serialize(transaction: &SerializableTransaction) -> Result<Vec<u8>, RuntimeError> {
+        serde_json::to_vec(transaction)
+            .map_err(|e| RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(e.to_string())))
+    }
+
+    async fn read(&self, tx_id: u32) -> Result<Option<SerializableTransaction>, RuntimeError> {
+        let contents = match tokio::fs::read(self.path(tx_id)).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(file_ops_error(e)),
+        };
+        serde_json::from_slice::<SerializableTransaction>(&contents)
+            .map(Some)
+            .map_err(|e| file_ops_error(format!("Corrupt transaction file {}: {}", tx_id, e)))
+    }
+
+    async fn write_new(&self, transaction: &SerializableTransaction) -> Result<(), RuntimeError> {
+        let contents = Self::serialize(transaction)?;
+        let path = self.path(transaction.tx_id);
+        // held until the file is written, so a journaled put always refers to a file this
+        // store created and rollback never removes one that was committed before
+        let _guard = self.journal_lock.lock().await;
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Err(RuntimeError::Recoverable(
+                RuntimeErrorType::TransactionAlreadyPresent,
+            ));
+        }
+        self.append_journal(&JournalEntry::Put(transaction.tx_id))
+            .await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await
+            .map_err(file_ops_error)?;
+        file.write_all(&contents)
+            .await
+            .map_err(|_| file_ops_error("Write to file failed"))
+    }
+
+    async fn overwrite(&self, transaction: &SerializableTransaction) -> Result<(), RuntimeError> {
+        // write aside and rename so a reader never sees a half written file
+        let contents = Self::serialize(transaction)?;
+        let temp_path = self.directory.join(format!("{}.tmp", transaction.tx_id));
+        tokio::fs::write(&temp_path, contents)
+            .await
+            .map_err(|_| file_ops_error("Write to file failed"))?;
+        tokio::fs::rename(&temp_path, self.path(transaction.tx_id))
+            .await
+            .map_err(file_ops_error)
+    }
+
+    /// Callers hold `journal_lock`
+    async fn append_journal(&self, entry: &JournalEntry) -> Result<(), RuntimeError> {
+        let mut journal = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.journal_path())
+            .await
+            .map_err(file_ops_error)?;
+        journal
+            .write_all(entry.to_string().as_bytes())
+            .await
+            .map_err(file_ops_error)
+    }
+
+    /// Callers hold `journal_lock`
+    async fn read_journal(&self) -> Result<Vec<JournalEntry>, RuntimeError> {
+        let contents = match tokio::fs::read_to_string(self.journal_path()).await {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(file_ops_error(e)),
+        };
+        // a torn last line was never acted upon, so it is skipped
+        Ok(contents
+            .split_terminator('\n')
+            .filter_map(|line| JournalEntry::from_str(line).ok())
+            .collect())
+    }
+
+    /// Callers hold `journal_lock`
+    async fn rewrite_journal(&self, entries: &[JournalEntry]) -> Result<(), RuntimeError> {
+        let contents: String = entries.iter().map(JournalEntry::to_string).collect();
+        let temp_path = self.directory.join(format!("{}.tmp", JOURNAL_FILE_NAME));
+        tokio::fs::write(&temp_path, contents)
+            .await
+            .map_err(file_ops_error)?;
+        tokio::fs::rename(&temp_path, self.journal_path())
+            .await
+            .map_err(file_ops_error)
+    }
+}
+
+impl TransactionStore for FileTransactionStore {
+    fn get(
+        &self,
+        tx_id: u32,
+    ) -> BoxFuture<'_, Result<Option<SerializableTransaction>, RuntimeError>> {
+        self.read(tx_id).boxed()
+    }
+
+    fn put(&self, transaction: SerializableTransaction) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        async move { self.write_new(&transaction).await }.boxed()
+    }
+
+    fn transition(
+        &self,
+        tx_id: u32,
+        transition: Transition,
+        at: Option<u64>,
+    ) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        async move {
+            let _guard = self.state_lock.lock().await;
+            let mut transaction = self.read(tx_id).await?.ok_or_else(not_present_error)?;
+            let previous_disputed_at = transaction.disputed_at;
+            transaction.transition(transition, at)?;
+            {
+                let _guard = self.journal_lock.lock().await;
+                self.append_journal(&JournalEntry::State(
+                    tx_id,
+                    transition.from(),
+                    previous_disputed_at,
+                ))
+                .await?;
+            }
+            self.overwrite(&transaction).await?;
+            self.with_open_disputes(|x| {
+                x.update(tx_id, transition.event(), transaction.disputed_at)
+            });
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn expired_disputes(
+        &self,
+        opened_before: u64,
+    ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        async move {
+            let tx_ids: Vec<u32> =
+                self.with_open_disputes(|x| x.opened_before(opened_before).collect());
+            let mut transactions = vec![];
+            for tx_id in tx_ids {
+                transactions.extend(self.read(tx_id).await?);
+            }
+            Ok(transactions)
+        }
+        .boxed()
+    }
+
+    fn transactions(&self) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        async move {
+            let mut entries = tokio::fs::read_dir(&self.directory)
+                .await
+                .map_err(file_ops_error)?;
+            let mut transactions = vec![];
+            while let Some(entry) = entries.next_entry().await.map_err(file_ops_error)? {
+                // skips the journal, checkpoints and half written temp files
+                let tx_id = match entry.file_name().to_str().map(str::parse::<u32>) {
+                    Some(Ok(x)) => x,
+                    _ => continue,
+                };
+                transactions.extend(self.read(tx_id).await?);
+            }
+            Ok(transactions)
+        }
+        .boxed()
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        async move {
+            let mut last_commit = self.journal_lock.lock().await;
+            tokio::fs::create_dir_all(&self.directory)
+                .await
+                .map_err(file_ops_error)?;
+            let mut entries = tokio::fs::read_dir(&self.directory)
+                .await
+                .map_err(file_ops_error)?;
+            while let Some(entry) = entries.next_entry().await.map_err(file_ops_error)? {
+                // only files this store writes, the directory may hold others
+                let name = entry.file_name();
+                let name = name.to_str().unwrap_or_default();
+                let ours = name == JOURNAL_FILE_NAME
+                    || name
+                        .strip_suffix(".tmp")
+                        .unwrap_or(name)
+                        .parse::<u32>()
+                        .is_ok()
+                    || name == format!("{}.tmp", JOURNAL_FILE_NAME);
+                if ours {
+                    tokio::fs::remove_file(entry.path())
+                        .await
+                        .map_err(file_ops_error)?;
+                }
+            }
+            *last_commit = 0;
+            self.with_open_disputes(OpenDisputes::clear);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, Result<u64, RuntimeError>> {
+        async move {
+            let mut last_commit = self.journal_lock.lock().await;
+            let previous = *last_commit;
+            *last_commit += 1;
+            self.append_journal(&JournalEntry::Commit(*last_commit))
+                .await?;
+            // undo entries before the previous commit can never be rolled back to again
+            let entries = self.read_journal().await?;
+            if let Some(start) = entries
+                .iter()
+                .position(|e| matches!(e, JournalEntry::Commit(x) if *x == previous))
+            {
+                self.rewrite_journal(&entries[start..]).await?;
+            }
+            Ok(*last_commit)
+        }
+        .boxed()
+    }
+
+    fn rollback(&self, marker: u64) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        async move {
+            let mut last_commit = self.journal_lock.lock().await;
+            let _state_guard = self.state_lock.lock().await;
+            let entries = self.read_journal().await?;
+            let start = entries
+                .iter()
+                .position(|e| matches!(e, JournalEntry::Commit(x) if *x == marker))
+                .ok_or_else(|| {
+                    RuntimeError::NonRecoverable(RuntimeErrorType::CheckpointError(format!(
+                        "Commit {} not found in the undo journal",
+                        marker
+                    )))
+                })?;
+            for entry in entries[start + 1..].iter().rev() {
+                match entry {
+                    JournalEntry::Put(tx_id) => {
+                        match tokio::fs::remove_file(self.path(*tx_id)).await {
+                            Err(e) if e.kind() != ErrorKind::NotFound => {
+                                return Err(file_ops_error(e))
+                            }
+                            _ => {}
+                        }
+                    }
+                    JournalEntry::State(tx_id, state, disputed_at) => {
+                        if let Some(mut transaction) = self.read(*tx_id).await? {
+                            // undone newest first, so the last event is the one to drop
+                            transaction.history.pop();
+                            transaction.state = *state;
+                            transaction.disputed_at = *disputed_at;
+                            self.overwrite(&transaction).await?;
+                        }
+                    }
+                    JournalEntry::Commit(_) => {}
+                }
+            }
+            self.rewrite_journal(&entries[start..start + 1]).await?;
+            *last_commit = marker;
+            let mut open_disputes = OpenDisputes::default();
+            for transaction in self.transactions().await? {
+                if transaction.state == SerializableState::Disputed {
+                    open_disputes.update(
+                        transaction.tx_id,
+                        DisputeEvent::Dispute,
+                        transaction.disputed_at,
+                    );
+                }
+            }
+            self.with_open_disputes(|x| *x = open_disputes);
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl std::fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalEntry::Put(tx_id) => writeln!(f, "put {}", tx_id),
+            JournalEntry::State(tx_id, state, None) => writeln!(f, "state {} {}", tx_id, state),
+            JournalEntry::State(tx_id, state, Some(at)) => {
+                writeln!(f, "state {} {} {}", tx_id, state, at)
+            }
+            JournalEntry::Commit(marker) => writeln!(f, "commit {}", marker),
+        }
+    }
+}
+
+impl FromStr for JournalEntry {
+    type Err = ();
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = line.split(' ').collect();
+        match parts.as_slice() {
+            ["put", tx_id] => Ok(JournalEntry::Put(tx_id.parse().map_err(|_| ())?)),
+            ["state", tx_id, state, at @ ..] if at.len() <= 1 => Ok(JournalEntry::State(
+                tx_id.parse().map_err(|_| ())?,
+                SerializableState::from_str(state).map_err(|_| ())?,
+                match at.first() {
+                    Some(x) => Some(x.parse().map_err(|_| ())?),
+                    None => None,
+                },
+            )),
+            ["commit", marker] => Ok(JournalEntry::Commit(marker.parse().map_err(|_| ())?)),
+            _ => Err(()),
+        }
+    }
+}
+
+fn file_ops_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::currency::Currency;
+    use crate::store::{Disputable, SerializableTransactionType};
+
+    #[tokio::test]
+    async fn rollback_restores_when_a_dispute_was_opened() -> Result<(), RuntimeError> {
+        let directory =
+            std::env::temp_dir().join(format!("file-store-rollback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let store = FileTransactionStore::new(&directory);
+        store.clear().await?;
+        store
+            .put(SerializableTransaction {
+                amount: Amount::from_units(10_000),
+                client_id: 1,
+                tx_id: 7,
+                transaction_type: SerializableTransactionType::Deposit,
+                state: SerializableState::Processed,
+                history: vec![],
+                destination: None,
+                currency: Currency::default(),
+                fee: Amount::ZERO,
+                timestamp: Some(0),
+                disputed_at: None,
+            })
+            .await?;
+        store
+            .transition(7, Transition::Dispute(Disputable::Processed), Some(10))
+            .await?;
+        let marker = store.commit().await?;
+        // resolved and disputed again in a batch that never made it to a checkpoint
+        store.transition(7, Transition::Resolve, Some(20)).await?;
+        store
+            .transition(7, Transition::Dispute(Disputable::Resolved), Some(50))
+            .await?;
+        store.rollback(marker).await?;
+
+        let transaction = store.get(7).await?.expect("committed before the marker");
+        assert_eq!(transaction.state, SerializableState::Disputed);
+        assert_eq!(transaction.disputed_at, Some(10));
+        assert_eq!(transaction.history, &[DisputeEvent::Dispute]);
+        assert_eq!(store.expired_disputes(11).await?.len(), 1);
+        std::fs::remove_dir_all(&directory).map_err(file_ops_error)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clear_only_removes_store_files() -> Result<(), RuntimeError> {
+        let directory =
+            std::env::temp_dir().join(format!("file-store-clear-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).map_err(file_ops_error)?;
+        std::fs::write(directory.join("notes.txt"), "keep me").map_err(file_ops_error)?;
+
+        let store = FileTransactionStore::new(&directory);
+        store
+            .put(SerializableTransaction {
+                amount: Amount::from_units(10_000),
+                client_id: 1,
+                tx_id: 7,
+                transaction_type: SerializableTransactionType::Deposit,
+                state: SerializableState::Processed,
+                history: vec![],
+                destination: None,
+                currency: Currency::default(),
+                fee: Amount::ZERO,
+                timestamp: None,
+                disputed_at: None,
+            })
+            .await?;
+        store.commit().await?;
+        store.clear().await?;
+
+        let mut left: Vec<String> = std::fs::read_dir(&directory)
+            .map_err(file_ops_error)?
+            .map(|x| {
+                x.expect("readable entry")
+                    .file_name()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        left.sort();
+        std::fs::remove_dir_all(&directory).map_err(file_ops_error)?;
+        assert_eq!(left, vec!["notes.txt".to_string()]);
+        assert!(store.get(7).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::store::{
+    illegal_transition_error, not_present_error, wrong_state_error, DisputeEvent, OpenDisputes,
+    SerializableState, SerializableTransaction, SerializableTransactionType, TransactionStore,
+    Transition,
+};
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const SEGMENT_FILE_NAME: &str = "transactions.log";
+
+const TRANSACTION_RECORD: u8 = 1;
+const EVENT_RECORD: u8 = 2;
+// kind, type, state, has timestamp, tx_id, client_id, destination + 1 (0 if none), padding,
+// amount units, currency, padding, fee units, timestamp
+const TRANSACTION_RECORD_LEN: usize = 48;
+// kind, dispute event, has time, padding, tx_id, time, offset of the previous event
+// record of the tx + 1 (0 if none)
+const EVENT_RECORD_LEN: usize = 24;
+
+/// Appends fixed size binary records to a single segment file and keeps a tx_id -> offset
+/// index in memory. State changes are appended as small records instead of rewriting the
+/// original one, so a transaction costs one write no matter how often it is disputed.
+/// Those records hold the `DisputeEvent`, replay turns it back into a `Transition`.
+/// The event records of a tx are chained backwards, the index only points at the latest
+/// one and the dispute history is read back from the segment.
+///
+/// File I/O runs on the blocking thread pool, never on the runtime's worker threads.
+/// A record torn by a crash at the tail of the segment is dropped on open.
+pub(crate) struct LogTransactionStore {
+    inner: Arc<Mutex<Segment>>,
+}
+
+struct Segment {
+    file: File,
+    len: u64,
+    index: HashMap<u32, IndexEntry>,
+    open_disputes: OpenDisputes,
+}
+
+struct IndexEntry {
+    offset: u64,
+    state: SerializableState,
+    disputed_at: Option<u64>,
+    last_event: Option<u64>, // offset of the latest event record
+}
+
+impl IndexEntry {
+    fn new(offset: u64, state: SerializableState) -> Self {
+        Self {
+            offset,
+            state,
+            disputed_at: None,
+            last_event: None,
+        }
+    }
+
+    fn apply(
+        &mut self,
+        tx_id: u32,
+        event: DisputeEvent,
+        at: Option<u64>,
+        offset: u64,
+    ) -> Result<(), RuntimeError> {
+        self.state = Transition::new(self.state, event)
+            .ok_or_else(|| illegal_transition_error(tx_id, self.state, event))?
+            .to();
+        self.last_event = Some(offset);
+        if event == DisputeEvent::Dispute {
+            self.disputed_at = at;
+        }
+        Ok(())
+    }
+}
+
+impl LogTransactionStore {
+    /// Opens or creates the segment in `directory` and rebuilds the index from it
+    pub(crate) fn open(directory: impl AsRef<Path>) -> Result<Self, RuntimeError> {
+        std::fs::create_dir_all(directory.as_ref()).map_err(file_ops_error)?;
+        let path = directory.as_ref().join(SEGMENT_FILE_NAME);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(file_ops_error)?;
+        let segment = Segment::replay(file)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(segment)),
+        })
+    }
+
+    /// Runs `f` on the segment from the blocking thread pool
+    fn with_segment<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Segment) -> Result<T, RuntimeError> + Send + 'static,
+    ) -> BoxFuture<'static, Result<T, RuntimeError>> {
+        let inner = self.inner.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let mut guard = inner
+                    .lock()
+                    .expect("No code panics while holding this lock");
+                f(&mut guard)
+            })
+            .await
+            .map_err(|e| RuntimeError::NonRecoverable(RuntimeErrorType::JoinError(e)))?
+        }
+        .boxed()
+    }
+}
+
+impl Segment {
+    fn replay(file: File) -> Result<Self, RuntimeError> {
+        let file_len = file.metadata().map_err(file_ops_error)?.len();
+        let mut segment = Self {
+            file,
+            len: 0,
+            index: HashMap::new(),
+            open_disputes: OpenDisputes::default(),
+        };
+        let mut buf = [0u8; TRANSACTION_RECORD_LEN];
+        while segment.len < file_len {
+            let remaining = file_len - segment.len;
+            read_at(&segment.file, &mut buf[..1], segment.len)?;
+            let record_len = match buf[0] {
+                TRANSACTION_RECORD => TRANSACTION_RECORD_LEN,
+                EVENT_RECORD => EVENT_RECORD_LEN,
+                // the file grew but the record never made it to disk
+                _ if segment.only_zeros_after(file_len)? => break,
+                kind => {
+                    return Err(file_ops_error(format!(
+                        "Unknown record kind {} at offset {}",
+                        kind, segment.len
+                    )))
+                }
+            };
+            if remaining < record_len as u64 {
+                break; // torn write at the tail, dropped below
+            }
+            read_at(&segment.file, &mut buf[..record_len], segment.len)?;
+            let replayed = match buf[0] {
+                TRANSACTION_RECORD => decode_transaction(&buf).map(|transaction| {
+                    segment.index.insert(
+                        transaction.tx_id,
+                        IndexEntry::new(segment.len, transaction.state),
+                    );
+                }),
+                _ => decode_event(&buf)
+                    .and_then(|(tx_id, event, at, _)| segment.apply(tx_id, event, at, segment.len)),
+            };
+            match replayed {
+                Ok(()) => {}
+                // a garbled last record was torn too
+                Err(_) if remaining == record_len as u64 => break,
+                Err(e) => return Err(e),
+            }
+            segment.len += record_len as u64;
+        }
+        if segment.len < file_len {
+            segment.file.set_len(segment.len).map_err(file_ops_error)?;
+        }
+        Ok(segment)
+    }
+
+    /// Whether every byte from the end of the segment to `file_len` is zero
+    fn only_zeros_after(&self, file_len: u64) -> Result<bool, RuntimeError> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(self.len))
+            .map_err(file_ops_error)?;
+        let mut rest = file.take(file_len - self.len);
+        let mut buf = [0u8; 4096];
+        loop {
+            let read = rest.read(&mut buf).map_err(file_ops_error)?;
+            if read == 0 {
+                return Ok(true);
+            }
+            if buf[..read].iter().any(|x| *x != 0) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Applies the event record at `offset` to the index
+    fn apply(
+        &mut self,
+        tx_id: u32,
+        event: DisputeEvent,
+        at: Option<u64>,
+        offset: u64,
+    ) -> Result<(), RuntimeError> {
+        if let Some(entry) = self.index.get_mut(&tx_id) {
+            entry.apply(tx_id, event, at, offset)?;
+            self.open_disputes.update(tx_id, event, entry.disputed_at);
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<u64, RuntimeError> {
+        let offset = self.len;
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset)).map_err(file_ops_error)?;
+        file.write_all(record).map_err(file_ops_error)?;
+        self.len += record.len() as u64;
+        Ok(offset)
+    }
+
+    fn read(&self, tx_id: u32) -> Result<Option<SerializableTransaction>, RuntimeError> {
+        let entry = match self.index.get(&tx_id) {
+            None => return Ok(None),
+            Some(x) => x,
+        };
+        let mut buf = [0u8; TRANSACTION_RECORD_LEN];
+        read_at(&self.file, &mut buf, entry.offset)?;
+        let mut transaction = decode_transaction(&buf)?;
+        transaction.state = entry.state;
+        transaction.history = self.history(entry.last_event)?;
+        transaction.disputed_at = entry.disputed_at;
+        Ok(Some(transaction))
+    }
+
+    /// Follows the chain of event records back from `last_event`, oldest event first
+    fn history(&self, mut last_event: Option<u64>) -> Result<Vec<DisputeEvent>, RuntimeError> {
+        let mut history = vec![];
+        let mut buf = [0u8; EVENT_RECORD_LEN];
+        while let Some(offset) = last_event {
+            read_at(&self.file, &mut buf, offset)?;
+            let (_, event, _, previous) = decode_event(&buf)?;
+            history.push(event);
+            last_event = previous;
+        }
+        history.reverse();
+        Ok(history)
+    }
+}
+
+impl TransactionStore for LogTransactionStore {
+    fn get(
+        &self,
+        tx_id: u32,
+    ) -> BoxFuture<'_, Result<Option<SerializableTransaction>, RuntimeError>> {
+        self.with_segment(move |segment| segment.read(tx_id))
+    }
+
+    fn put(&self, transaction: SerializableTransaction) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        self.with_segment(move |segment| {
+            if segment.index.contains_key(&transaction.tx_id) {
+                return Err(RuntimeError::Recoverable(
+                    RuntimeErrorType::TransactionAlreadyPresent,
+                ));
+            }
+            let offset = segment.append(&encode_transaction(&transaction))?;
+            segment.index.insert(
+                transaction.tx_id,
+                IndexEntry::new(offset, transaction.state),
+            );
+            Ok(())
+        })
+    }
+
+    fn transition(
+        &self,
+        tx_id: u32,
+        transition: Transition,
+        at: Option<u64>,
+    ) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        self.with_segment(move |segment| {
+            let entry = segment.index.get(&tx_id).ok_or_else(not_present_error)?;
+            if entry.state != transition.from() {
+                return Err(wrong_state_error(tx_id, transition.from(), entry.state));
+            }
+            let record = encode_event(tx_id, transition.event(), at, entry.last_event);
+            let offset = segment.append(&record)?;
+            segment.apply(tx_id, transition.event(), at, offset)
+        })
+    }
+
+    fn expired_disputes(
+        &self,
+        opened_before: u64,
+    ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        self.with_segment(move |segment| {
+            let mut transactions = vec![];
+            for tx_id in segment.open_disputes.opened_before(opened_before) {
+                transactions.extend(segment.read(tx_id)?);
+            }
+            Ok(transactions)
+        })
+    }
+
+    fn transactions(&self) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        self.with_segment(|segment| {
+            let mut transactions = vec![];
+            for tx_id in segment.index.keys() {
+                transactions.extend(segment.read(*tx_id)?);
+            }
+            Ok(transactions)
+        })
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        self.with_segment(|segment| {
+            segment.file.set_len(0).map_err(file_ops_error)?;
+            segment.len = 0;
+            segment.index.clear();
+            segment.open_disputes.clear();
+            Ok(())
+        })
+    }
+
+    fn commit(&self) -> BoxFuture<'_, Result<u64, RuntimeError>> {
+        self.with_segment(|segment| {
+            segment.file.sync_data().map_err(file_ops_error)?;
+            Ok(segment.len)
+        })
+    }
+
+    fn rollback(&self, marker: u64) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        self.with_segment(move |segment| {
+            if marker > segment.len {
+                return Err(RuntimeError::NonRecoverable(
+                    RuntimeErrorType::CheckpointError(format!(
+                        "Segment is {} bytes but checkpoint expects {}",
+                        segment.len, marker
+                    )),
+                ));
+            }
+            segment.file.set_len(marker).map_err(file_ops_error)?;
+            *segment = Segment::replay(segment.file.try_clone().map_err(file_ops_error)?)?;
+            Ok(())
+        })
+    }
+}
+
+/// Fills `buf` from `offset`. Callers hold the segment lock, which keeps the file cursor theirs.
+fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> Result<(), RuntimeError> {
+    file.seek(SeekFrom::Start(offset)).map_err(file_ops_error)?;
+    file.read_exact(buf).map_err(file_ops_error)
+}
+
+fn encode_transaction(transaction: &SerializableTransaction) -> [u8; TRANSACTION_RECORD_LEN] {
+    let mut buf = [0u8; TRANSACTION_RECORD_LEN];
+    buf[0] = TRANSACTION_RECORD;
+    buf[1] = encode_transaction_type(transaction.transaction_type);
+    buf[2] = encode_state_byte(transaction.state);
+    buf[4..8].copy_from_slice(&transaction.tx_id.to_le_bytes());
+    buf[8..10].copy_from_slice(&transaction.client_id.to_le_bytes());
+    let destination = transaction.destination.map_or(0, |x| u32::from(x) + 1);
+    buf[10..14].copy_from_slice(&destination.to_le_bytes());
+    buf[16..24].copy_from_slice(&transaction.amount.units().to_le_bytes());
+    buf[24..27].copy_from_slice(&transaction.currency.bytes());
+    buf[32..40].copy_from_slice(&transaction.fee.units().to_le_bytes());
+    if let Some(x) = transaction.timestamp {
+        buf[3] = 1;
+        buf[40..48].copy_from_slice(&x.to_le_bytes());
+    }
+    buf
+}
+
+fn decode_transaction(buf: &[u8]) -> Result<SerializableTransaction, RuntimeError> {
+    let units = i64::from_le_bytes(buf[16..24].try_into().expect("8 byte slice"));
+    let fee = i64::from_le_bytes(buf[32..40].try_into().expect("8 byte slice"));
+    let destination = u32::from_le_bytes(buf[10..14].try_into().expect("4 byte slice"));
+    Ok(SerializableTransaction {
+        amount: Amount::from_units(units),
+        client_id: u16::from_le_bytes(buf[8..10].try_into().expect("2 byte slice")),
+        tx_id: u32::from_le_bytes(buf[4..8].try_into().expect("4 byte slice")),
+        transaction_type: decode_transaction_type(buf[1])?,
+        state: decode_state_byte(buf[2])?,
+        history: vec![],
+        destination: destination.checked_sub(1).map(|x| x as u16),
+        currency: Currency::from_bytes(buf[24..27].try_into().expect("3 byte slice"))?,
+        fee: Amount::from_units(fee),
+        timestamp: decode_time(buf[3], &buf[40..48]),
+        disputed_at: None,
+    })
+}
+
+fn encode_event(
+    tx_id: u32,
+    event: DisputeEvent,
+    at: Option<u64>,
+    previous: Option<u64>,
+) -> [u8; EVENT_RECORD_LEN] {
+    let mut buf = [0u8; EVENT_RECORD_LEN];
+    buf[0] = EVENT_RECORD;
+    buf[1] = encode_event_byte(event);
+    if let Some(x) = at {
+        buf[2] = 1;
+        buf[8..16].copy_from_slice(&x.to_le_bytes());
+    }
+    buf[4..8].copy_from_slice(&tx_id.to_le_bytes());
+    buf[16..24].copy_from_slice(&previous.map_or(0, |x| x + 1).to_le_bytes());
+    buf
+}
+
+/// tx_id, event, when it happened and the offset of the previous event record of the tx
+fn decode_event(buf: &[u8]) -> Result<(u32, DisputeEvent, Option<u64>, Option<u64>), RuntimeError> {
+    let previous = u64::from_le_bytes(buf[16..24].try_into().expect("8 byte slice"));
+    Ok((
+        u32::from_le_bytes(buf[4..8].try_into().expect("4 byte slice")),
+        decode_event_byte(buf[1])?,
+        decode_time(buf[2], &buf[8..16]),
+        previous.checked_sub(1),
+    ))
+}
+
+/// A time field, `None` unless its flag byte is set
+fn decode_time(flag: u8, buf: &[u8]) -> Option<u64> {
+    (flag == 1).then(|| u64::from_le_bytes(buf.try_into().expect("8 byte slice")))
+}
+
+fn encode_transaction_type(transaction_type: SerializableTransactionType) -> u8 {
+    match transaction_type {
+        SerializableTransactionType::Deposit => 0,
+        SerializableTransactionType::Withdrawal => 1,
+        SerializableTransactionType::Transfer => 2,
+    }
+}
+
+fn decode_transaction_type(byte: u8) -> Result<SerializableTransactionType, RuntimeError> {
+    match byte {
+        0 => Ok(SerializableTransactionType::Deposit),
+        1 => Ok(SerializableTransactionType::Withdrawal),
+        2 => Ok(SerializableTransactionType::Transfer),
+        x => Err(file_ops_error(format!("Unknown transaction type {}", x))),
+    }
+}
+
+fn encode_state_byte(state: SerializableState) -> u8 {
+    match state {
+        SerializableState::Processed => 1,
+        SerializableState::Disputed => 2,
+        SerializableState::ChargedBack => 3,
+        SerializableState::Resolved => 4,
+        SerializableState::Rejected => 5,
+    }
+}
+
+fn decode_state_byte(byte: u8) -> Result<SerializableState, RuntimeError> {
+    match byte {
+        1 => Ok(SerializableState::Processed),
+        2 => Ok(SerializableState::Disputed),
+        3 => Ok(SerializableState::ChargedBack),
+        4 => Ok(SerializableState::Resolved),
+        5 => Ok(SerializableState::Rejected),
+        x => Err(file_ops_error(format!("Unknown transaction state {}", x))),
+    }
+}
+
+fn encode_event_byte(event: DisputeEvent) -> u8 {
+    match event {
+        DisputeEvent::Dispute => 1,
+        DisputeEvent::Resolve => 2,
+        DisputeEvent::Chargeback => 3,
+    }
+}
+
+fn decode_event_byte(byte: u8) -> Result<DisputeEvent, RuntimeError> {
+    match byte {
+        1 => Ok(DisputeEvent::Dispute),
+        2 => Ok(DisputeEvent::Resolve),
+        3 => Ok(DisputeEvent::Chargeback),
+        x => Err(file_ops_error(format!("Unknown dispute event {}", x))),
+    }
+}
+
+fn file_ops_error(e: impl ToString) -> RuntimeError {
+    RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFileOps(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Disputable;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn reopen_replays_transactions_and_state_changes() -> Result<(), RuntimeError> {
+        let directory = std::env::temp_dir().join(format!("log-store-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        {
+            let store = LogTransactionStore::open(&directory)?;
+            store
+                .put(SerializableTransaction {
+                    amount: Amount::from_str("1.2345")?,
+                    client_id: 7,
+                    tx_id: u32::MAX,
+                    transaction_type: SerializableTransactionType::Deposit,
+                    state: SerializableState::Processed,
+                    history: vec![],
+                    destination: None,
+                    currency: Currency::default(),
+                    fee: Amount::from_units(2_500),
+                    timestamp: Some(1_000),
+                    disputed_at: None,
+                })
+                .await?;
+            for (transition, at) in [
+                (Transition::Dispute(Disputable::Processed), 2_000),
+                (Transition::Resolve, 3_000),
+                (Transition::Dispute(Disputable::Resolved), 4_000),
+            ] {
+                store.transition(u32::MAX, transition, Some(at)).await?;
+            }
+            // already disputed again, so the stored state no longer matches
+            let stale = store
+                .transition(u32::MAX, Transition::Dispute(Disputable::Resolved), None)
+                .await;
+            assert!(matches!(stale, Err(RuntimeError::Recoverable(_))));
+        }
+        // a torn record at the tail is dropped on open, as are zeros the record never filled
+        let segment_path = directory.join(SEGMENT_FILE_NAME);
+        let append = |bytes: &[u8]| {
+            OpenOptions::new()
+                .append(true)
+                .open(&segment_path)
+                .and_then(|mut x| x.write_all(bytes))
+                .map_err(file_ops_error)
+        };
+        append(&[TRANSACTION_RECORD, 0, 1])?;
+        drop(LogTransactionStore::open(&directory)?);
+        append(&[0; 60])?;
+
+        let store = LogTransactionStore::open(&directory)?;
+        let transaction = store
+            .get(u32::MAX)
+            .await?
+            .expect("replayed from the segment");
+        assert_eq!(transaction.amount, Amount::from_units(12_345));
+        assert_eq!(transaction.client_id, 7);
+        assert_eq!(transaction.fee, Amount::from_units(2_500));
+        assert_eq!(transaction.state, SerializableState::Disputed);
+        assert_eq!(transaction.timestamp, Some(1_000));
+        assert_eq!(transaction.disputed_at, Some(4_000));
+        assert!(store.expired_disputes(4_000).await?.is_empty());
+        assert_eq!(store.expired_disputes(4_001).await?.len(), 1);
+        assert_eq!(
+            transaction.history,
+            &[
+                DisputeEvent::Dispute,
+                DisputeEvent::Resolve,
+                DisputeEvent::Dispute
+            ]
+        );
+        assert_eq!(
+            std::fs::metadata(directory.join(SEGMENT_FILE_NAME))
+                .map_err(file_ops_error)?
+                .len(),
+            120
+        );
+
+        std::fs::remove_dir_all(&directory).map_err(file_ops_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::store::{
+    not_present_error, OpenDisputes, SerializableTransaction, TransactionStore, Transition,
+};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Keeps every transaction in a `HashMap`. Nothing touches disk, so it suits tests and
+/// inputs whose transactions fit in memory.
+#[derive(Default)]
+pub(crate) struct MemoryTransactionStore {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    transactions: HashMap<u32, SerializableTransaction>,
+    open_disputes: OpenDisputes,
+}
+
+impl MemoryTransactionStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_inner<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> T {
+        let mut guard = self
+            .inner
+            .lock()
+            .expect("No code panics while holding this lock");
+        f(&mut guard)
+    }
+}
+
+impl TransactionStore for MemoryTransactionStore {
+    fn get(
+        &self,
+        tx_id: u32,
+    ) -> BoxFuture<'_, Result<Option<SerializableTransaction>, RuntimeError>> {
+        let result = self.with_inner(|inner| inner.transactions.get(&tx_id).cloned());
+        future::ready(Ok(result)).boxed()
+    }
+
+    fn put(&self, transaction: SerializableTransaction) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        let result = self.with_inner(|inner| {
+            if inner.transactions.contains_key(&transaction.tx_id) {
+                return Err(RuntimeError::Recoverable(
+                    RuntimeErrorType::TransactionAlreadyPresent,
+                ));
+            }
+            inner.transactions.insert(transaction.tx_id, transaction);
+            Ok(())
+        });
+        future::ready(result).boxed()
+    }
+
+    fn transition(
+        &self,
+        tx_id: u32,
+        transition: Transition,
+        at: Option<u64>,
+    ) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        let result = self.with_inner(|inner| {
+            let t = inner
+                .transactions
+                .get_mut(&tx_id)
+                .ok_or_else(not_present_error)?;
+            t.transition(transition, at)?;
+            inner
+                .open_disputes
+                .update(tx_id, transition.event(), t.disputed_at);
+            Ok(())
+        });
+        future::ready(result).boxed()
+    }
+
+    fn expired_disputes(
+        &self,
+        opened_before: u64,
+    ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        let result = self.with_inner(|inner| {
+            inner
+                .open_disputes
+                .opened_before(opened_before)
+                .filter_map(|tx_id| inner.transactions.get(&tx_id).cloned())
+                .collect()
+        });
+        future::ready(Ok(result)).boxed()
+    }
+
+    fn transactions(&self) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>> {
+        let result = self.with_inner(|inner| inner.transactions.values().cloned().collect());
+        future::ready(Ok(result)).boxed()
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        self.with_inner(|inner| {
+            inner.transactions.clear();
+            inner.open_disputes.clear();
+        });
+        future::ready(Ok(())).boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, Result<u64, RuntimeError>> {
+        future::ready(Ok(0)).boxed()
+    }
+
+    fn rollback(&self, _marker: u64) -> BoxFuture<'_, Result<(), RuntimeError>> {
+        future::ready(Err(RuntimeError::NonRecoverable(
+            RuntimeErrorType::CheckpointError(
+                "In memory store does not survive a restart".to_string(),
+            ),
+        )))
+        .boxed()
+    }
+}
@@ -0,0 +1,374 @@
+use crate::amount::Amount;
+use crate::currency::Currency;
+use crate::error::{RuntimeError, RuntimeErrorType};
+use crate::transaction::{CSVTransactionType, State1};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+mod file_store;
+mod log_store;
+mod memory_store;
+
+pub(crate) use file_store::FileTransactionStore;
+pub(crate) use log_store::{LogTransactionStore, SEGMENT_FILE_NAME};
+pub(crate) use memory_store::MemoryTransactionStore;
+
+/// Where transactions are kept so disputes can find them, see `EngineConfig::store`
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumString,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum StoreBackend {
+    /// One JSON file per transaction in the scratch directory
+    #[default]
+    File,
+    /// Fixed size records appended to a single file in the scratch directory
+    Log,
+    /// A `HashMap`, nothing touches disk and a run can't be resumed
+    Memory,
+}
+
+/// Backend holding every deposit and withdrawal so later disputes can find them.
+/// Implementations are shared between client tasks and must be safe to call concurrently.
+pub(crate) trait TransactionStore: Send + Sync {
+    /// Returns the stored transaction or `None` if `tx_id` was never written
+    fn get(
+        &self,
+        tx_id: u32,
+    ) -> BoxFuture<'_, Result<Option<SerializableTransaction>, RuntimeError>>;
+
+    /// Writes a new transaction. Fails with `TransactionAlreadyPresent` if `tx_id` is taken
+    fn put(&self, transaction: SerializableTransaction) -> BoxFuture<'_, Result<(), RuntimeError>>;
+
+    /// Applies `transition` to `tx_id`. Fails with `WrongTransactionState` if the stored
+    /// state is no longer the one `transition` starts from. `at` is when it happened, a
+    /// dispute keeps it so it can expire.
+    fn transition(
+        &self,
+        tx_id: u32,
+        transition: Transition,
+        at: Option<u64>,
+    ) -> BoxFuture<'_, Result<(), RuntimeError>>;
+
+    /// Disputed transactions whose dispute was opened before `opened_before`, oldest
+    /// dispute first. Stores keep an index of open disputes by time for this, disputes
+    /// without a time never expire.
+    fn expired_disputes(
+        &self,
+        opened_before: u64,
+    ) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>>;
+
+    /// Every stored transaction, so reports can tell how each one ended
+    fn transactions(&self) -> BoxFuture<'_, Result<Vec<SerializableTransaction>, RuntimeError>>;
+
+    /// Drops every stored transaction
+    fn clear(&self) -> BoxFuture<'_, Result<(), RuntimeError>>;
+
+    /// Persists every write made so far and returns a marker for them. Only the marker
+    /// of the latest checkpoint has to stay valid for `rollback`.
+    fn commit(&self) -> BoxFuture<'_, Result<u64, RuntimeError>>;
+
+    /// Discards every write made after `marker` was committed. Called when resuming so
+    /// writes from an interrupted batch are not trusted.
+    fn rollback(&self, marker: u64) -> BoxFuture<'_, Result<(), RuntimeError>>;
+}
+
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumIter,
+    strum::EnumString,
+    strum::EnumVariantNames,
+    strum::IntoStaticStr,
+)]
+pub(crate) enum SerializableTransactionType {
+    Deposit,
+    Withdrawal,
+    /// From `client_id` to `destination`. Disputed by the destination like a deposit.
+    Transfer,
+}
+
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::Display,
+    strum::EnumIter,
+    strum::EnumString,
+    strum::EnumVariantNames,
+    strum::IntoStaticStr,
+)]
+/// Where a stored transaction is in its lifecycle. Only a `Transition` moves between states,
+/// and only the store sets them.
+pub(crate) enum SerializableState {
+    Processed,   // applied, never disputed
+    Disputed,    // under dispute, its amount is held
+    Resolved,    // dispute resolved, can be disputed again up to the dispute limit
+    ChargedBack, // final
+    Rejected,    // not applied, kept so its tx_id stays taken. Final
+}
+
+/// States a transaction can be disputed from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Disputable {
+    Processed,
+    Resolved,
+}
+
+/// A legal step in the lifecycle of a stored transaction. Each variant only holds the
+/// states its event is allowed from, so an illegal transition can't be written down and
+/// stores are never handed one. The state it leads to follows from the variant.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Transition {
+    /// Processed or Resolved -> Disputed
+    Dispute(Disputable),
+    /// Disputed -> Resolved
+    Resolve,
+    /// Disputed -> ChargedBack
+    Chargeback,
+}
+
+impl Transition {
+    /// The transition `event` makes from `state`, `None` if it is not allowed from there.
+    /// Used where the state is only known at runtime: a stored transaction a row refers
+    /// to, or an event replayed from disk.
+    pub(crate) fn new(state: SerializableState, event: DisputeEvent) -> Option<Self> {
+        match (state, event) {
+            (SerializableState::Processed, DisputeEvent::Dispute) => {
+                Some(Transition::Dispute(Disputable::Processed))
+            }
+            (SerializableState::Resolved, DisputeEvent::Dispute) => {
+                Some(Transition::Dispute(Disputable::Resolved))
+            }
+            (SerializableState::Disputed, DisputeEvent::Resolve) => Some(Transition::Resolve),
+            (SerializableState::Disputed, DisputeEvent::Chargeback) => Some(Transition::Chargeback),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from(self) -> SerializableState {
+        match self {
+            Transition::Dispute(Disputable::Processed) => SerializableState::Processed,
+            Transition::Dispute(Disputable::Resolved) => SerializableState::Resolved,
+            Transition::Resolve | Transition::Chargeback => SerializableState::Disputed,
+        }
+    }
+
+    pub(crate) fn to(self) -> SerializableState {
+        match self {
+            Transition::Dispute(_) => SerializableState::Disputed,
+            Transition::Resolve => SerializableState::Resolved,
+            Transition::Chargeback => SerializableState::ChargedBack,
+        }
+    }
+
+    pub(crate) fn event(self) -> DisputeEvent {
+        match self {
+            Transition::Dispute(_) => DisputeEvent::Dispute,
+            Transition::Resolve => DisputeEvent::Resolve,
+            Transition::Chargeback => DisputeEvent::Chargeback,
+        }
+    }
+}
+
+/// One step in the dispute history of a transaction
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize, strum::Display,
+)]
+pub(crate) enum DisputeEvent {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct SerializableTransaction {
+    pub(crate) amount: Amount,
+    pub(crate) client_id: u16,
+    pub(crate) tx_id: u32,
+    pub(crate) transaction_type: SerializableTransactionType,
+    state: SerializableState, // only the store moves it, see `Transition`
+    #[serde(default)]
+    pub(crate) history: Vec<DisputeEvent>, // kept by the store on every state change
+    #[serde(default)]
+    pub(crate) destination: Option<u16>, // client a transfer went to
+    #[serde(default)]
+    pub(crate) currency: Currency,
+    #[serde(default)]
+    pub(crate) fee: Amount, // charged on top of a withdrawal or taken from a deposit
+    #[serde(default)]
+    pub(crate) timestamp: Option<u64>, // unix seconds, if the input has them
+    #[serde(default)]
+    pub(crate) disputed_at: Option<u64>, // when the latest dispute was opened
+}
+
+impl SerializableTransaction {
+    pub(crate) fn state(&self) -> SerializableState {
+        self.state
+    }
+
+    /// Dispute cycles started so far, including resolved ones
+    pub(crate) fn dispute_count(&self) -> usize {
+        self.history
+            .iter()
+            .filter(|e| **e == DisputeEvent::Dispute)
+            .count()
+    }
+
+    /// Applies `transition` if the transaction is still in the state it starts from and
+    /// records it
+    fn transition(&mut self, transition: Transition, at: Option<u64>) -> Result<(), RuntimeError> {
+        if self.state != transition.from() {
+            return Err(wrong_state_error(self.tx_id, transition.from(), self.state));
+        }
+        self.state = transition.to();
+        self.history.push(transition.event());
+        if let Transition::Dispute(_) = transition {
+            self.disputed_at = at;
+        }
+        Ok(())
+    }
+
+    /// Marks a transaction that was not applied, so it is stored as `Rejected`
+    pub(crate) fn rejected(mut self) -> Self {
+        self.state = SerializableState::Rejected;
+        self
+    }
+
+    pub(crate) fn new_from_state1(transaction: &dyn State1) -> Result<Self, RuntimeError> {
+        let csv_transaction = transaction.inner();
+        let transaction_type = match csv_transaction.transaction_type {
+            CSVTransactionType::Deposit => SerializableTransactionType::Deposit,
+            CSVTransactionType::Withdrawal => SerializableTransactionType::Withdrawal,
+            CSVTransactionType::Transfer => SerializableTransactionType::Transfer,
+            _ => {
+                return Err(RuntimeError::Recoverable(
+                    RuntimeErrorType::WrongTransactionState(
+                        "Ignoring this transaction".to_string(),
+                    ),
+                ))
+            }
+        };
+        Ok(Self {
+            amount: csv_transaction
+                .amount
+                .expect("State 1 transactions have an amount"),
+            client_id: csv_transaction.client_id,
+            tx_id: csv_transaction.tx_id,
+            transaction_type,
+            state: SerializableState::Processed,
+            history: vec![],
+            destination: csv_transaction.destination,
+            currency: csv_transaction.currency,
+            fee: Amount::ZERO,
+            timestamp: csv_transaction.timestamp,
+            disputed_at: None,
+        })
+    }
+}
+
+/// Open disputes that have a time, ordered by it, so expired ones are found without
+/// going through every transaction
+#[derive(Default)]
+pub(crate) struct OpenDisputes(BTreeSet<(u64, u32)>);
+
+impl OpenDisputes {
+    /// Keeps the index in step with `event`, given when the transaction was last disputed
+    /// once the event was applied
+    pub(crate) fn update(&mut self, tx_id: u32, event: DisputeEvent, disputed_at: Option<u64>) {
+        if let Some(at) = disputed_at {
+            match event {
+                DisputeEvent::Dispute => self.0.insert((at, tx_id)),
+                DisputeEvent::Resolve | DisputeEvent::Chargeback => self.0.remove(&(at, tx_id)),
+            };
+        }
+    }
+
+    pub(crate) fn opened_before(&self, opened_before: u64) -> impl Iterator<Item = u32> + '_ {
+        self.0.range(..(opened_before, 0)).map(|(_, tx_id)| *tx_id)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn wrong_state_error(
+    tx_id: u32,
+    expected: SerializableState,
+    found: SerializableState,
+) -> RuntimeError {
+    RuntimeError::Recoverable(RuntimeErrorType::WrongTransactionState(format!(
+        "Transaction {} expected in {} but found in {}",
+        tx_id, expected, found
+    )))
+}
+
+fn illegal_transition_error(
+    tx_id: u32,
+    current: SerializableState,
+    event: DisputeEvent,
+) -> RuntimeError {
+    RuntimeError::Recoverable(RuntimeErrorType::WrongTransactionState(format!(
+        "Transaction {} can't take a {} in {}",
+        tx_id, event, current
+    )))
+}
+
+fn not_present_error() -> RuntimeError {
+    RuntimeError::Recoverable(RuntimeErrorType::WrongTransactionState(
+        "Transaction not present".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn transitions_round_trip_through_their_state_and_event() {
+        for state in SerializableState::iter() {
+            for event in [
+                DisputeEvent::Dispute,
+                DisputeEvent::Resolve,
+                DisputeEvent::Chargeback,
+            ] {
+                if let Some(transition) = Transition::new(state, event) {
+                    assert_eq!(transition.from(), state);
+                    assert_eq!(transition.event(), event);
+                }
+            }
+        }
+        for state in [SerializableState::ChargedBack, SerializableState::Rejected] {
+            assert_eq!(Transition::new(state, DisputeEvent::Dispute), None);
+        }
+        assert_eq!(
+            Transition::Dispute(Disputable::Resolved).to(),
+            SerializableState::Disputed
+        );
+    }
+}
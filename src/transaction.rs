@@ -1,6 +1,7 @@
+use crate::amount::Amount;
+use crate::currency::Currency;
 use crate::error::RuntimeError::NonRecoverable;
 use crate::error::{RuntimeError, RuntimeErrorType};
-use bigdecimal::{BigDecimal, Zero};
 use csv::StringRecord;
 use std::convert::TryFrom;
 use std::str::FromStr;
@@ -29,22 +30,109 @@ pub enum CSVTransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    Unlock,
+    Freeze,
+    Unfreeze,
 }
 
 #[derive(Debug)]
 pub struct CSVTransaction {
     // Represents a read transaction from file
-    pub(crate) amount: Option<BigDecimal>,
+    pub(crate) amount: Option<Amount>,
     pub(crate) client_id: u16,
     pub(crate) tx_id: u32,
     pub(crate) transaction_type: CSVTransactionType,
+    pub(crate) reason: Option<String>,   // only read for admin rows
+    pub(crate) destination: Option<u16>, // only read for transfers
+    pub(crate) currency: Currency,
+    pub(crate) timestamp: Option<u64>, // unix seconds, if the input has them
+}
+
+impl CSVTransaction {
+    /// A transaction fed in process rather than read from a csv row. `amount` is only
+    /// used by deposits and withdrawals.
+    pub fn new(
+        transaction_type: CSVTransactionType,
+        client_id: u16,
+        tx_id: u32,
+        amount: Option<Amount>,
+    ) -> Self {
+        Self {
+            amount,
+            client_id,
+            tx_id,
+            transaction_type,
+            reason: None,
+            destination: None,
+            currency: Currency::default(),
+            timestamp: None,
+        }
+    }
+
+    /// Currency of `amount`, the default one unless set
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// When the transaction happened, in seconds since the unix epoch
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Client a transfer moves `amount` to, `client_id` being the one it comes from
+    pub fn with_destination(mut self, destination: u16) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// Why an admin row (`unlock`, `freeze`, `unfreeze`) was issued, kept in the audit
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    pub fn transaction_type(&self) -> CSVTransactionType {
+        self.transaction_type
+    }
+
+    pub fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    pub fn tx_id(&self) -> u32 {
+        self.tx_id
+    }
+
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn destination(&self) -> Option<u16> {
+        self.destination
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
 }
 
 impl TryFrom<StringRecord> for CSVTransaction {
     type Error = RuntimeError;
 
     fn try_from(record: StringRecord) -> Result<Self, Self::Error> {
-        //type, client, tx, amount
+        //type, client, tx, amount, reason (admin rows) or destination client (transfers), currency,
+        //timestamp
         let transaction_type = {
             match record.get(0) {
                 None => {
@@ -93,19 +181,53 @@ impl TryFrom<StringRecord> for CSVTransaction {
                 .map_err(|e| NonRecoverable(RuntimeErrorType::CSVLineParseError(e.to_string())))?
         };
         let amount = {
-            match record.get(3) {
+            match record.get(3).map(str::trim) {
                 None => None,
                 Some("") => None,
-                Some(x) => Some(BigDecimal::from_str(x.trim()).map_err(|e| {
-                    NonRecoverable(RuntimeErrorType::CSVLineParseError(e.to_string()))
+                Some(x) => Some(Amount::from_str(x).map_err(|e| {
+                    NonRecoverable(RuntimeErrorType::CSVLineParseError(
+                        e.into_type().to_string(),
+                    ))
                 })?),
             }
         };
+        let (reason, destination) = match (transaction_type, record.get(4).map(str::trim)) {
+            (_, None) | (_, Some("")) => (None, None),
+            (CSVTransactionType::Transfer, Some(x)) => {
+                let destination = u16::from_str(x).map_err(|_e| {
+                    NonRecoverable(RuntimeErrorType::CSVLineParseError(
+                        "Parse destination client_id".to_string(),
+                    ))
+                })?;
+                (None, Some(destination))
+            }
+            (_, Some(x)) => (Some(x.to_string()), None),
+        };
+        let currency = match record.get(5).map(str::trim) {
+            None | Some("") => Currency::default(),
+            Some(x) => Currency::from_str(x).map_err(|e| {
+                NonRecoverable(RuntimeErrorType::CSVLineParseError(
+                    e.into_type().to_string(),
+                ))
+            })?,
+        };
+        let timestamp = match record.get(6).map(str::trim) {
+            None | Some("") => None,
+            Some(x) => Some(u64::from_str(x).map_err(|_e| {
+                NonRecoverable(RuntimeErrorType::CSVLineParseError(
+                    "Parse timestamp".to_string(),
+                ))
+            })?),
+        };
         Ok(Self {
             amount,
             client_id,
             tx_id,
             transaction_type,
+            reason,
+            destination,
+            currency,
+            timestamp,
         })
     }
 }
@@ -119,6 +241,10 @@ pub struct DepositRequest(pub(crate) CSVTransaction);
 pub struct DisputeRequest(pub(crate) CSVTransaction);
 pub struct ResolveRequest(pub(crate) CSVTransaction);
 pub struct ChargeBackRequest(pub(crate) CSVTransaction);
+pub struct TransferRequest(pub(crate) CSVTransaction);
+pub struct UnlockRequest(pub(crate) CSVTransaction);
+pub struct FreezeRequest(pub(crate) CSVTransaction);
+pub struct UnfreezeRequest(pub(crate) CSVTransaction);
 
 impl State1 for DepositRequest {
     fn inner(&self) -> &CSVTransaction {
@@ -130,6 +256,11 @@ impl State1 for WithdrawalRequest {
         &self.0
     }
 }
+impl State1 for TransferRequest {
+    fn inner(&self) -> &CSVTransaction {
+        &self.0
+    }
+}
 //
 // impl State2 for DisputeRequest {}
 //
@@ -146,7 +277,7 @@ impl TryFrom<CSVTransaction> for WithdrawalRequest {
                 )))
             }
             Some(x) => {
-                if x < &BigDecimal::zero() {
+                if x.is_negative() {
                     return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
                         "Amount not present".to_string(),
                     )));
@@ -173,7 +304,7 @@ impl TryFrom<CSVTransaction> for DepositRequest {
                 )))
             }
             Some(x) => {
-                if x < &BigDecimal::zero() {
+                if x.is_negative() {
                     return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
                         "Amount not present".to_string(),
                     )));
@@ -190,6 +321,48 @@ impl TryFrom<CSVTransaction> for DepositRequest {
     }
 }
 
+impl TryFrom<CSVTransaction> for TransferRequest {
+    type Error = RuntimeError;
+    fn try_from(value: CSVTransaction) -> Result<Self, Self::Error> {
+        match &value.amount {
+            Some(x) if !x.is_negative() => {}
+            _ => {
+                return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+                    "Amount not present".to_string(),
+                )))
+            }
+        }
+        match value.destination {
+            None => {
+                return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+                    "Destination not present".to_string(),
+                )))
+            }
+            Some(x) if x == value.client_id => {
+                return Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+                    "Transfer to the same client".to_string(),
+                )))
+            }
+            Some(_) => {}
+        }
+
+        match value.transaction_type {
+            CSVTransactionType::Transfer => Ok(TransferRequest(value)),
+            _ => Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                "Not a transfer".to_string(),
+            ))),
+        }
+    }
+}
+
+impl TransferRequest {
+    pub(crate) fn destination(&self) -> u16 {
+        self.0
+            .destination
+            .expect("Transfer request makes sure this is there")
+    }
+}
+
 impl TryFrom<CSVTransaction> for DisputeRequest {
     type Error = RuntimeError;
     fn try_from(value: CSVTransaction) -> Result<Self, Self::Error> {
@@ -225,3 +398,52 @@ impl TryFrom<CSVTransaction> for ChargeBackRequest {
         }
     }
 }
+
+/// Admin rows are only applied with a reason, so the audit always says why
+fn ensure_reason(value: &CSVTransaction) -> Result<(), RuntimeError> {
+    match value.reason.as_deref() {
+        Some(x) if !x.is_empty() => Ok(()),
+        _ => Err(RuntimeError::NonRecoverable(RuntimeErrorType::ParseError(
+            "Reason not present".to_string(),
+        ))),
+    }
+}
+
+impl TryFrom<CSVTransaction> for UnlockRequest {
+    type Error = RuntimeError;
+    fn try_from(value: CSVTransaction) -> Result<Self, Self::Error> {
+        ensure_reason(&value)?;
+        match value.transaction_type {
+            CSVTransactionType::Unlock => Ok(UnlockRequest(value)),
+            _ => Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                "Not an unlock".to_string(),
+            ))),
+        }
+    }
+}
+
+impl TryFrom<CSVTransaction> for FreezeRequest {
+    type Error = RuntimeError;
+    fn try_from(value: CSVTransaction) -> Result<Self, Self::Error> {
+        ensure_reason(&value)?;
+        match value.transaction_type {
+            CSVTransactionType::Freeze => Ok(FreezeRequest(value)),
+            _ => Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                "Not a freeze".to_string(),
+            ))),
+        }
+    }
+}
+
+impl TryFrom<CSVTransaction> for UnfreezeRequest {
+    type Error = RuntimeError;
+    fn try_from(value: CSVTransaction) -> Result<Self, Self::Error> {
+        ensure_reason(&value)?;
+        match value.transaction_type {
+            CSVTransactionType::Unfreeze => Ok(UnfreezeRequest(value)),
+            _ => Err(RuntimeError::Recoverable(RuntimeErrorType::ParseError(
+                "Not an unfreeze".to_string(),
+            ))),
+        }
+    }
+}
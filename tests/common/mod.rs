@@ -0,0 +1,29 @@
+//! Fixtures shared by the integration tests
+#![allow(dead_code)] // every test crate uses its own share of them
+
+use rust_test::{Amount, EngineConfig, Input, Runner, StoreBackend};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub fn amount(x: &str) -> Amount {
+    Amount::from_str(x).expect("valid amount")
+}
+
+/// Reads `csv` as the whole input
+pub fn csv_input(csv: &str) -> Input {
+    Input::from_reader(std::io::Cursor::new(csv.to_string()))
+}
+
+/// Runner keeping its transactions in memory, so no scratch directory is left behind
+pub fn in_memory(input: impl Into<Input>) -> Runner {
+    let config = EngineConfig {
+        store: StoreBackend::Memory,
+        ..EngineConfig::default()
+    };
+    Runner::new(input, &config).expect("valid config")
+}
+
+/// A file of this test process in the temp directory, `name` telling apart the tests
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}-{}", std::process::id(), name))
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use common::{amount, csv_input, in_memory, temp_path};
+use rust_test::{
+    Currency, EngineConfig, ErrorPolicy, History, HistoryWriter, Input, JournalWriter,
+    LedgerAccount, RejectionReport, ReportFormat, RuntimeError, RuntimeErrorType,
+};
+use std::error::Error;
+use std::fs::{self, File};
+
+#[tokio::test]
+async fn journal_balances_against_the_accounts() -> Result<(), Box<dyn Error>> {
+    let config = EngineConfig::parse(
+        r#"
+dispute_policy = "withdrawal-reversal"
+fee_policy = "refund"
+[fees]
+deposit = [{ flat = "0.5" }]
+"#,
+    )?;
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 3.0
+transfer, 1, 3, 2.0, 2
+dispute, 1, 2,
+chargeback, 1, 2,
+dispute, 2, 3,
+chargeback, 2, 3,
+deposit, 3, 4, 4.0
+deposit, 3, 5, 1.0
+dispute, 3, 4,
+chargeback, 3, 4,
+";
+    let journal_path = temp_path("journal.csv");
+    let mut runner = in_memory(csv_input(csv))
+        .with_config(&config)
+        .with_journal(JournalWriter::create(&journal_path, ReportFormat::Csv)?);
+    runner.run().await?;
+
+    let usd = Currency::default();
+    let ledger = runner.ledger();
+    // 15 came in and 3 went out, then 4 went back to the card network. The reversed
+    // withdrawal is a loss, one of three deposit fees was refunded.
+    assert_eq!(
+        ledger.balance(LedgerAccount::ExternalCash, usd),
+        amount("8")
+    );
+    assert_eq!(
+        ledger.balance(LedgerAccount::ChargebackLoss, usd),
+        amount("3")
+    );
+    assert_eq!(ledger.balance(LedgerAccount::FeeIncome, usd), amount("-1"));
+    assert_eq!(
+        ledger.balance(LedgerAccount::ClientAvailable(1), usd),
+        amount("-9.5")
+    );
+    drop(runner);
+
+    let journal = fs::read_to_string(&journal_path)?;
+    assert_eq!(
+        journal.lines().nth(1),
+        Some("1,deposit,USD,external-cash,client-available:1,10.0000")
+    );
+    assert!(journal.contains("4,chargeback,USD,fee-income,client-available:3,0.5000"));
+    fs::remove_file(&journal_path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn history_tells_the_balance_at_any_line() -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+transfer, 1, 3, 5.0, 2
+withdrawal, 1, 4, 2.0
+dispute, 2, 3,
+";
+    let history_path = temp_path("history.jsonl");
+    let mut runner = in_memory(csv_input(csv))
+        .with_batch_size(2)
+        .with_history(HistoryWriter::open(&history_path)?);
+    runner.run().await?;
+
+    let usd = Currency::default();
+    let history = History::read(std::io::BufReader::new(File::open(&history_path)?))?;
+    fs::remove_file(&history_path)?;
+    // what the rejected withdrawal was up against
+    assert_eq!(
+        history.balance_before_tx(1, 4).map(|x| x.available),
+        Some(amount("1"))
+    );
+    assert_eq!(history.events(1)[3].error.as_deref(), Some("BalanceIssues"));
+    assert_eq!(history.balance_at_line(1, usd, 3).available, amount("6"));
+    assert_eq!(history.balance_at_line(2, usd, 3).available, amount("0"));
+    assert_eq!(history.balance_at_line(2, usd, 4).available, amount("5"));
+    let y = history.balance_at_line(2, usd, 6);
+    assert_eq!((y.available, y.held), (amount("0"), amount("5")));
+    assert_eq!(history.events(2).len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejected_rows_are_reported_with_line_and_reason() -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 9.0
+deposit, 1, 1, 1.0
+refund, 1, 3, 1.0
+deposit, 2, 4,
+resolve, 1, 1,
+";
+    let report_path = temp_path("rejections.jsonl");
+    let mut runner = in_memory(csv_input(csv)).with_rejection_report(RejectionReport::create(
+        &report_path,
+        ReportFormat::JsonLines,
+    )?);
+    runner.run().await?;
+    drop(runner);
+
+    let report = fs::read_to_string(&report_path)?;
+    let mut rejections: Vec<serde_json::Value> = report
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    // clients report independently, so only each write is in line order
+    rejections.sort_by_key(|r| r["line"].as_u64());
+    let summary: Vec<(u64, &str)> = rejections
+        .iter()
+        .map(|r| (r["line"].as_u64().unwrap(), r["error"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (3, "BalanceIssues"),
+            (4, "TransactionAlreadyPresent"),
+            (5, "CSVLineParseError"),
+            (6, "ParseError"),
+            (7, "WrongTransactionState"),
+        ]
+    );
+    assert_eq!(rejections[0]["record"], "withdrawal, 1, 2, 9.0");
+    fs::remove_file(&report_path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn halted_append_reports_everything_and_refuses_more() -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 8.0
+dispute, 1, 1,
+deposit, 2, 3, 5.0
+";
+    let report_path = temp_path("halted-rejections.csv");
+    let journal_path = temp_path("halted-journal.csv");
+    let mut runner = in_memory(Input::from_reader(std::io::empty()))
+        .with_error_policy(ErrorPolicy::Halt)
+        .with_rejection_report(RejectionReport::create(&report_path, ReportFormat::Csv)?)
+        .with_journal(JournalWriter::create(&journal_path, ReportFormat::Csv)?);
+    let result = runner.append(csv_input(csv)).await;
+    assert!(matches!(
+        result,
+        Err(RuntimeError::NonRecoverable(
+            RuntimeErrorType::TransactionFailed { tx_id: 1, .. }
+        ))
+    ));
+    let result = runner
+        .append(Input::from_reader(std::io::Cursor::new(
+            "type, client, tx, amount\ndeposit, 3, 4, 1.0\n",
+        )))
+        .await;
+    assert!(matches!(
+        result,
+        Err(RuntimeError::NonRecoverable(RuntimeErrorType::Halted(_)))
+    ));
+    drop(runner);
+
+    let report = fs::read_to_string(&report_path)?;
+    assert!(report.contains("dispute, 1, 1,"));
+    assert!(report.contains("BalanceIssues"));
+    let journal = fs::read_to_string(&journal_path)?;
+    for tx in ["1,deposit", "2,withdrawal", "3,deposit"] {
+        assert!(journal.contains(tx), "{} missing from the journal", tx);
+    }
+    fs::remove_file(&report_path)?;
+    fs::remove_file(&journal_path)?;
+    Ok(())
+}
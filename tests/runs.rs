@@ -0,0 +1,151 @@
+mod common;
+
+use common::{amount, csv_input, in_memory};
+use rust_test::{
+    Amount, EngineConfig, ErrorPolicy, Input, OutputFormat, Runner, RuntimeError, RuntimeErrorType,
+};
+use std::error::Error;
+use std::path::PathBuf;
+
+#[tokio::test]
+async fn concurrent_runners_use_separate_scratch_directories() -> Result<(), Box<dyn Error>> {
+    let mut a = Runner::new(PathBuf::from("data/sample.csv"), &EngineConfig::default())?;
+    let mut b = Runner::new(PathBuf::from("data/sample.csv"), &EngineConfig::default())?;
+    let a_directory = a.scratch_directory().unwrap().to_path_buf();
+    assert_ne!(a_directory, b.scratch_directory().unwrap());
+
+    let (x, y) = tokio::join!(a.run(), b.run());
+    x?;
+    y?;
+    for runner in [&a, &b] {
+        let x = runner.get_cloned_account_snapshot(1).await;
+        assert_eq!(x.as_ref().unwrap().available(), amount("100.5"));
+    }
+
+    drop(a);
+    assert!(!a_directory.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reads_from_any_reader() -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount\ndeposit, 1, 1, 2.0\nwithdrawal, 1, 2, 0.5\n";
+    let input = csv_input(csv);
+    let mut runner = in_memory(input);
+    runner.run().await?;
+    let x = runner.get_cloned_account_snapshot(1).await;
+    assert_eq!(x.as_ref().unwrap().available(), amount("1.5"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn streams_async_input_in_batches() -> Result<(), Box<dyn Error>> {
+    use tokio::io::AsyncWriteExt;
+
+    // the pipe holds far less than the whole input, so it has to be read in batches
+    let (mut writer, reader) = tokio::io::duplex(4096);
+    let producer = tokio::spawn(async move {
+        writer.write_all(b"type, client, tx, amount\n").await?;
+        for tx in 1..=25_000 {
+            let line = format!("deposit, {}, {}, 1.0\n", tx % 3, tx);
+            writer.write_all(line.as_bytes()).await?;
+        }
+        std::io::Result::Ok(())
+    });
+
+    let input = Input::from_async_reader(reader);
+    let mut runner = in_memory(input);
+    runner.run().await?;
+    producer.await??;
+    for (client, deposits) in [(0, 8_333), (1, 8_334), (2, 8_333)] {
+        let x = runner.get_cloned_account_snapshot(client).await;
+        assert_eq!(
+            x.as_ref().unwrap().available(),
+            Amount::from_units(deposits * 10_000)
+        );
+    }
+    Ok(())
+}
+
+async fn run_with_policy(error_policy: ErrorPolicy) -> (Runner, Result<(), RuntimeError>) {
+    // disputing tx 1 needs 10 available but only 2 are left
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 8.0
+dispute, 1, 1,
+deposit, 1, 3, 1.0
+deposit, 2, 4, 5.0
+";
+    let mut runner = in_memory(csv_input(csv)).with_error_policy(error_policy);
+    let result = runner.run().await;
+    (runner, result)
+}
+
+#[tokio::test]
+async fn halt_policy_fails_the_run_with_client_and_tx() {
+    let (_, result) = run_with_policy(ErrorPolicy::Halt).await;
+    match result {
+        Err(RuntimeError::NonRecoverable(RuntimeErrorType::TransactionFailed {
+            client_id,
+            tx_id,
+            error,
+        })) => {
+            assert_eq!((client_id, tx_id), (1, 1));
+            assert!(matches!(*error, RuntimeErrorType::BalanceIssues(_)));
+        }
+        x => panic!("expected a TransactionFailed error, got {:?}", x),
+    }
+}
+
+#[tokio::test]
+async fn freeze_policy_only_stops_the_failing_client() -> Result<(), Box<dyn Error>> {
+    let (runner, result) = run_with_policy(ErrorPolicy::Freeze).await;
+    result?;
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+    assert!(x.frozen());
+    assert_eq!(x.available(), amount("2"));
+    assert_eq!(y.available(), amount("5"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn skip_policy_carries_on_with_the_client() -> Result<(), Box<dyn Error>> {
+    let (runner, result) = run_with_policy(ErrorPolicy::Skip).await;
+    result?;
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    assert!(!x.frozen());
+    assert_eq!(x.available(), amount("3"));
+    assert_eq!(x.held(), amount("0"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn output_is_sorted_with_fixed_scale() -> Result<(), Box<dyn Error>> {
+    let mut runner = Runner::new(PathBuf::from("data/sample.csv"), &EngineConfig::default())?;
+    runner.run().await?;
+
+    let mut csv = vec![];
+    runner
+        .write_accounts(&mut csv, OutputFormat::Csv, 4)
+        .await?;
+    assert_eq!(
+        String::from_utf8(csv)?,
+        "client,currency,available,held,total,locked
+1,USD,100.5000,0.0000,100.5000,false
+2,USD,1.0000,0.0000,1.0000,false
+"
+    );
+
+    let mut json_lines = vec![];
+    runner
+        .write_accounts(&mut json_lines, OutputFormat::JsonLines, 2)
+        .await?;
+    assert_eq!(
+        String::from_utf8(json_lines)?.lines().next(),
+        Some(
+            r#"{"client":1,"currency":"USD","available":"100.50","held":"0.00","total":"100.50","locked":false}"#
+        )
+    );
+    Ok(())
+}
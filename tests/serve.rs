@@ -0,0 +1,233 @@
+mod common;
+
+use common::{amount, in_memory};
+use futures::future;
+use rust_test::{ErrorPolicy, ExpiredDisputePolicy, FeedServer, HttpServer, Input};
+use std::error::Error;
+
+async fn request(
+    method: hyper::Method,
+    uri: String,
+    content_type: &str,
+    body: &str,
+) -> Result<(hyper::StatusCode, serde_json::Value), Box<dyn Error>> {
+    let request = hyper::Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(hyper::header::CONTENT_TYPE, content_type)
+        .body(hyper::Body::from(body.to_string()))?;
+    let response = hyper::Client::new().request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let json = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+    Ok((status, json))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_transactions_and_accounts_over_http() -> Result<(), Box<dyn Error>> {
+    use hyper::{Method, StatusCode};
+
+    let runner = in_memory(Input::from_reader(std::io::empty()));
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = format!("http://{}", listener.local_addr()?);
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(HttpServer::new(runner).serve(listener, async {
+        let _ = stopped.await;
+    }));
+
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 3.0
+withdrawal, 2, 3, 5.0
+";
+    let (status, body) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "text/csv",
+        csv,
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["rejected"][0]["line"], 4);
+    assert_eq!(body["rejected"][0]["error"], "BalanceIssues");
+
+    // disputes reach the transactions of earlier requests
+    let batch = r#"[
+        {"type": "transfer", "client": 1, "tx": 4, "amount": "2.5", "destination": 2},
+        {"type": "dispute", "client": 2, "tx": 2},
+        {"type": "withdrawal", "client": 2, "tx": 5, "amount": "5.0"}
+    ]"#;
+    let (status, body) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "application/json",
+        batch,
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["rejected"][0]["line"], 3);
+    let (_, body) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "application/json",
+        r#"{"type": "resolve", "client": 2, "tx": 2}"#,
+    )
+    .await?;
+    assert_eq!(body["rejected"], serde_json::json!([]));
+
+    let (status, body) = request(Method::GET, format!("{}/accounts/1", address), "", "").await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["available"], "7.5000");
+    assert_eq!(body[0]["held"], "0.0000");
+    let (_, body) = request(Method::GET, format!("{}/accounts", address), "", "").await?;
+    assert_eq!(body[1]["client"], 2);
+    assert_eq!(body[1]["available"], "5.5000");
+    let (status, _) = request(Method::GET, format!("{}/accounts/9", address), "", "").await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "application/json",
+        "{",
+    )
+    .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // a number is taken as an amount, a destination is never dropped for a reason
+    let (_, body) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "application/json",
+        r#"{"type": "deposit", "client": 3, "tx": 6, "amount": 1.5}"#,
+    )
+    .await?;
+    assert_eq!(body["rejected"], serde_json::json!([]));
+    let (status, _) = request(
+        Method::POST,
+        format!("{}/transactions", address),
+        "application/json",
+        r#"{"type": "transfer", "client": 3, "tx": 7, "amount": "1.0", "reason": "rent", "destination": 1}"#,
+    )
+    .await?;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (_, body) = request(Method::GET, format!("{}/accounts/3", address), "", "").await?;
+    assert_eq!(body[0]["available"], "1.5000");
+
+    let _ = stop.send(());
+    server.await??;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn feed_acknowledges_every_line_in_order() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let runner =
+        in_memory(Input::from_reader(std::io::empty())).with_error_policy(ErrorPolicy::Skip);
+    let server = FeedServer::new(runner);
+    let runner = server.runner();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(server.serve(listener, async {
+        let _ = stopped.await;
+    }));
+
+    // every producer deposits to its own client then withdraws more than it has,
+    // all of them on client 100 as well
+    let producers = (0..4u32).map(|producer| async move {
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        let (reader, mut writer) = stream.into_split();
+        let base = producer * 1_000;
+        let mut sent = String::new();
+        for i in 0..50 {
+            sent.push_str(&format!("deposit, {}, {}, 1.0\n", producer, base + i));
+            sent.push_str(&format!("deposit, 100, {}, 1.0\n", base + 100 + i));
+        }
+        sent.push_str(&format!("withdrawal, {}, {}, 51.0\n", producer, base + 200));
+        sent.push_str(&format!("dispute, {}, {}\n", producer, base + 49));
+        sent.push_str("refund, 1, 2, 1.0\n");
+        writer.write_all(sent.as_bytes()).await?;
+        writer.shutdown().await?;
+        let mut replies = vec![];
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            replies.push(line);
+        }
+        Ok::<_, Box<dyn Error + Send + Sync>>((base, replies))
+    });
+    for (base, replies) in future::try_join_all(producers)
+        .await
+        .map_err(|e| e as Box<dyn Error>)?
+    {
+        assert_eq!(replies.len(), 103);
+        assert_eq!(replies[0], format!("ACK {}", base));
+        assert_eq!(replies[99], format!("ACK {}", base + 149));
+        assert_eq!(replies[100], format!("NACK {} BalanceIssues", base + 200));
+        assert_eq!(replies[101], format!("ACK {}", base + 49));
+        assert_eq!(replies[102], "NACK 2 CSVLineParseError");
+    }
+
+    let runner = runner.lock().await;
+    let x = runner.get_cloned_account_snapshot(100).await.unwrap();
+    assert_eq!(x.available(), amount("200"));
+    let y = runner.get_cloned_account_snapshot(3).await.unwrap();
+    assert_eq!((y.available(), y.held()), (amount("49"), amount("1")));
+    drop(runner);
+    let _ = stop.send(());
+    server.await??;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn feed_answers_for_the_row_alone() -> Result<(), Box<dyn Error>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let runner = in_memory(Input::from_reader(std::io::empty()))
+        .with_error_policy(ErrorPolicy::Skip)
+        .with_dispute_deadline(10, ExpiredDisputePolicy::Chargeback);
+    let server = FeedServer::new(runner);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?;
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(server.serve(listener, async {
+        let _ = stopped.await;
+    }));
+
+    let exchange = |sent: String| async move {
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(sent.as_bytes()).await?;
+        writer.shutdown().await?;
+        let mut replies = vec![];
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            replies.push(line);
+        }
+        Ok::<_, Box<dyn Error>>(replies)
+    };
+    // the second chargeback settling an expired dispute finds the account locked,
+    // which is not the deposit's doing
+    let replies = exchange(
+        "deposit, 1, 1, 5.0,, USD, 0
+deposit, 1, 2, 5.0,, USD, 0
+dispute, 1, 1,,, USD, 1
+dispute, 1, 2,,, USD, 1
+deposit, 2,\"3\", 1.0,, USD, 100
+"
+        .to_string(),
+    )
+    .await?;
+    assert_eq!(replies, ["ACK 1", "ACK 2", "ACK 1", "ACK 2", "ACK 3"]);
+
+    let replies = exchange(format!(
+        "deposit, 3, 4, {}\ndeposit, 3, 5, 1.0\n",
+        "1".repeat(5_000)
+    ))
+    .await?;
+    assert_eq!(replies, ["NACK - CSVLineParseError"]);
+
+    let _ = stop.send(());
+    server.await??;
+    Ok(())
+}
@@ -0,0 +1,301 @@
+mod common;
+
+use common::{amount, csv_input, in_memory, temp_path};
+use rust_test::{
+    AdminAction, AuditRecord, Currency, EngineConfig, OutputFormat, RejectionReport, ReportFormat,
+    Runner, StoreBackend,
+};
+use std::error::Error;
+use std::fs;
+use std::str::FromStr;
+
+#[tokio::test]
+async fn transfers_move_funds_and_can_be_charged_back() -> Result<(), Box<dyn Error>> {
+    let mut csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+transfer, 1, 3, 4.0, 2
+transfer, 2, 4, 1.0, 1
+dispute, 2, 3,
+chargeback, 2, 3,
+transfer, 1, 5, 100.0, 2
+deposit, 3, 6, 1.0
+"
+    .to_string();
+    // clients 3 and 4 pass the same amount back and forth across many small batches
+    for tx in 8..2_008 {
+        let (from, to) = if tx % 2 == 0 { (3, 4) } else { (4, 3) };
+        csv.push_str(&format!("transfer, {}, {}, 1.0, {}\n", from, tx, to));
+    }
+    let config = EngineConfig {
+        batch_size: 7,
+        ..EngineConfig::default()
+    };
+    let mut runner = Runner::new(csv_input(&csv), &config)?;
+    runner.run().await?;
+
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+    assert_eq!(x.available(), amount("11"));
+    assert_eq!(y.available(), amount("4"));
+    assert_eq!(y.held(), amount("0"));
+    assert!(y.locked());
+    let z = runner.get_cloned_account_snapshot(3).await.unwrap();
+    let w = runner.get_cloned_account_snapshot(4).await.unwrap();
+    assert_eq!(z.available(), amount("1"));
+    assert_eq!(w.available(), amount("0"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejected_transfers_open_no_account_and_senders_come_from_the_store(
+) -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0
+transfer, 1, 2, 100.0, 3
+transfer, 1, 3, 2.0, 2
+dispute, 2, 3,
+chargeback, 2, 3,
+";
+    // every row a batch of its own, so the chargeback only finds its sender in the store
+    let config = EngineConfig {
+        batch_size: 1,
+        store: StoreBackend::Memory,
+        ..EngineConfig::default()
+    };
+    let mut runner = Runner::new(csv_input(csv), &config)?;
+    runner.run().await?;
+
+    assert!(runner.get_cloned_account_snapshot(3).await.is_none());
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+    assert_eq!(x.available(), amount("5"));
+    assert_eq!(y.total()?, amount("0"));
+    assert!(y.locked());
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_rows_unlock_and_freeze_accounts() -> Result<(), Box<dyn Error>> {
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 2, 1.0
+unlock, 1, 3, , chargeback reversed by the card network
+deposit, 1, 4, 2.0
+freeze, 2, 5, , fraud review
+deposit, 2, 6, 1.0
+unfreeze, 2, 7,
+";
+    let mut runner = in_memory(csv_input(csv));
+    runner.run().await?;
+
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    assert!(!x.locked());
+    assert_eq!(x.available(), amount("2"));
+    assert_eq!(
+        x.audit(),
+        &[AuditRecord {
+            tx_id: 3,
+            action: AdminAction::Unlock,
+            reason: "chargeback reversed by the card network".to_string(),
+        }]
+    );
+    // the unfreeze has no reason, so the account stays frozen
+    let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+    assert!(y.frozen());
+    assert_eq!(y.available(), amount("0"));
+    assert_eq!(y.audit()[0].action, AdminAction::Freeze);
+    Ok(())
+}
+
+#[tokio::test]
+async fn fees_are_charged_and_refunded_on_chargeback() -> Result<(), Box<dyn Error>> {
+    let config = EngineConfig::parse(
+        r#"
+fee_policy = "refund"
+
+[fees]
+deposit = [{ up_to = "10", flat = "0.5" }, { flat = "0" }]
+withdrawal = [{ up_to = "5", flat = "0.1" }, { percent = "10" }]
+"#,
+    )?;
+    // the withdrawal of 10 costs 11, the deposit of 20 falls in the free last tier
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 20.0
+withdrawal, 1, 3, 2.0
+withdrawal, 1, 4, 10.0
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 2, 5, 0.4
+deposit, 2, 6, 20.0
+deposit, 2, 7, 0.4
+";
+    let mut runner = in_memory(csv_input(csv)).with_config(&config);
+    runner.run().await?;
+
+    let usd = Currency::default();
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    // 9.5 + 20 - 2.1 - 11 - 10 held and charged back + 0.5 refunded
+    assert_eq!(x.available(), amount("6.9"));
+    assert_eq!(x.held(), amount("0"));
+    assert_eq!(x.fees(usd), amount("1.1"));
+    assert!(x.locked());
+    // too small to pay its own fee, even with enough already in the account
+    let y = runner.get_cloned_account_snapshot(2).await.unwrap();
+    assert_eq!(y.available(), amount("20"));
+    assert_eq!(y.fees(usd), amount("0"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn withdrawals_breaking_limits_are_rejected() -> Result<(), Box<dyn Error>> {
+    let config = EngineConfig::parse(
+        r#"
+[limits]
+max_amount = "50"
+window = 4
+max_count = 2
+max_sum = "30"
+min_balance = "5"
+"#,
+    )?;
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 60.0
+withdrawal, 1, 3, 20.0
+withdrawal, 1, 4, 20.0
+withdrawal, 1, 5, 10.0
+withdrawal, 1, 6, 5.0
+withdrawal, 1, 7, 10.0
+deposit, 2, 8, 10.0
+withdrawal, 2, 9, 6.0
+";
+    let report_path = temp_path("limits.jsonl");
+    let mut runner = in_memory(csv_input(csv))
+        .with_config(&config)
+        .with_rejection_report(RejectionReport::create(
+            &report_path,
+            ReportFormat::JsonLines,
+        )?);
+    runner.run().await?;
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    assert_eq!(x.available(), amount("60"));
+    drop(runner);
+
+    let report = fs::read_to_string(&report_path)?;
+    let mut rejections: Vec<serde_json::Value> = report
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    rejections.sort_by_key(|r| r["line"].as_u64());
+    let summary: Vec<(u64, &str)> = rejections
+        .iter()
+        .map(|r| (r["line"].as_u64().unwrap(), r["error"].as_str().unwrap()))
+        .collect();
+    // above max_amount, above max_sum within the window, a third withdrawal within
+    // the window and one leaving less than min_balance
+    assert_eq!(
+        summary,
+        vec![
+            (3, "LimitExceeded"),
+            (5, "LimitExceeded"),
+            (7, "LimitExceeded"),
+            (10, "LimitExceeded"),
+        ]
+    );
+    fs::remove_file(&report_path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn balances_are_kept_per_currency() -> Result<(), Box<dyn Error>> {
+    // the EUR withdrawal can't use the USD balance
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, 3.0, , eur
+withdrawal, 1, 3, 4.0, , EUR
+dispute, 1, 2,
+deposit, 2, 4, 1.0, , GBP
+";
+    let mut runner = in_memory(csv_input(csv));
+    runner.run().await?;
+
+    let eur = Currency::from_str("EUR")?;
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    assert_eq!(x.available(), amount("5"));
+    assert_eq!(x.balance(eur).available, amount("0"));
+    assert_eq!(x.balance(eur).held, amount("3"));
+
+    let mut csv = vec![];
+    runner
+        .write_accounts(&mut csv, OutputFormat::Csv, 1)
+        .await?;
+    assert_eq!(
+        String::from_utf8(csv)?,
+        "client,currency,available,held,total,locked
+1,EUR,0.0,3.0,3.0,false
+1,USD,5.0,0.0,5.0,false
+2,GBP,1.0,0.0,1.0,false
+"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_disputes_are_charged_back() -> Result<(), Box<dyn Error>> {
+    let config = EngineConfig::parse(
+        r#"
+dispute_window_days = 10
+dispute_deadline_days = 2
+expired_disputes = "chargeback"
+"#,
+    )?;
+    // days 0, 1, 2 and 12. The dispute of tx 2 comes a day after its window closed,
+    // the one of tx 1 is past its deadline once the clock reaches day 12.
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0, , , 0
+deposit, 1, 2, 3.0, , , 86400
+dispute, 1, 1, , , , 172800
+dispute, 1, 2, , , , 1036800
+";
+    let mut runner = in_memory(csv_input(csv))
+        .with_config(&config)
+        .with_batch_size(2);
+    runner.run().await?;
+
+    let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+    assert_eq!(x.available(), amount("3"));
+    assert_eq!(x.held(), amount("0"));
+    assert!(x.locked());
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_disputes_settle_before_the_row_moving_the_clock() -> Result<(), Box<dyn Error>> {
+    let config = EngineConfig::parse(
+        r#"
+dispute_deadline_days = 2
+expired_disputes = "resolve"
+"#,
+    )?;
+    // the dispute is resolved on day 5 before the withdrawal needs its funds
+    let csv = "type, client, tx, amount
+deposit, 1, 1, 5.0, , , 0
+dispute, 1, 1, , , , 0
+withdrawal, 1, 2, 4.0, , , 432000
+";
+    for batch_size in [1, 1_000] {
+        let mut runner = in_memory(csv_input(csv))
+            .with_config(&config)
+            .with_batch_size(batch_size);
+        runner.run().await?;
+
+        let x = runner.get_cloned_account_snapshot(1).await.unwrap();
+        assert_eq!(x.available(), amount("1"), "batch size {}", batch_size);
+        assert_eq!(x.held(), amount("0"));
+    }
+    Ok(())
+}